@@ -0,0 +1,422 @@
+//! adversarial checks of request authentication (auth-test)
+//!
+//! every other subcommand only sends requests signed correctly by `fitbod::auth::sign_request`.
+//! auth-test instead sends requests that must be rejected: bad signatures, signatures made with
+//! another user's key, missing or duplicated auth headers, stale and future timestamps, bodies
+//! altered after signing, and requests for a user other than the one whose key signed them.
+//! each is sent to both endpoints, and the server must:
+//!
+//! - reject it with one of the statuses expected for the case
+//! - not include any workouts in the rejection
+//! - not store anything from it (checked by listing both users' workouts before and after)
+
+use std::time::*;
+use std::path::Path;
+use chrono::prelude::*;
+use rand::prelude::*;
+use uuid::Uuid;
+
+use crate::http::{self, ApiClient, HttpOpts};
+use crate::metrics::{MetricsOpts, MetricsSink};
+use crate::oracle::{self, ListWindow};
+
+const LIST: &str = "/api/v1/workouts/list";
+const NEW: &str = "/api/v1/workouts/new";
+
+const SIGNATURE_HEADER: &str = "x-fitbod-access-signature";
+const TIMESTAMP_HEADER: &str = "x-fitbod-access-timestamp";
+
+/// statuses accepted for a request whose signature doesn't check out
+const UNAUTHORIZED: &[u16] = &[401, 403];
+/// statuses accepted for a request with missing or ambiguous auth headers
+const BAD_AUTH_HEADERS: &[u16] = &[400, 401];
+/// statuses accepted for a correctly signed request for workouts of another user
+const FORBIDDEN: &[u16] = &[400, 401, 403];
+
+/// workouts written for each of the two users before the checks, so there is data to leak
+const N_SEEDED: usize = 3;
+
+/// change made to the auth headers of a correctly rendered request
+enum HeaderEdit {
+    None,
+    Replace(&'static str, String),
+    Remove(&'static [&'static str]),
+    /// adds a second header with this name and value, before or after the original
+    Duplicate { name: &'static str, value: String, first: bool },
+}
+
+/// how a request is signed and altered before it's sent
+struct Forgery {
+    /// body the signature is computed over
+    signed: String,
+    /// body actually sent. differs from `signed` when the body is tampered with.
+    sent: String,
+    key: fitbod::auth::PrivateKey,
+    timestamp: i64,
+    edit: HeaderEdit,
+}
+
+impl Forgery {
+    fn new<T: serde::Serialize>(req: &T, key: fitbod::auth::PrivateKey) -> Self {
+        let body = serde_json::to_string(req).unwrap();
+        Self { signed: body.clone(), sent: body, key, timestamp: Utc::now().timestamp(), edit: HeaderEdit::None }
+    }
+
+    fn signature(&self) -> String {
+        fitbod::auth::sign_request(self.timestamp, &self.signed, &self.key)
+    }
+
+    /// the full text of the http request (in the same format as every other request)
+    fn render(&self, tera: &tera::Tera, path: &str) -> String {
+        let mut ctx = tera::Context::new();
+        ctx.insert("path", path);
+        ctx.insert("body", &self.sent);
+        ctx.insert("sig", &self.signature());
+        ctx.insert("timestamp", &self.timestamp.to_string());
+        ctx.insert("connection", "close");
+        let rendered = tera.render("api-request", &ctx).unwrap();
+
+        // headers are written back with whatever line ending the template uses
+        let (split, eol) = match rendered.find("\r\n\r\n") {
+            Some(i) => (i, "\r\n"),
+            None => (rendered.find("\n\n").expect("request has no body"), "\n"),
+        };
+        let (head, body) = rendered.split_at(split);
+        let mut lines: Vec<String> = Vec::new();
+        for line in head.split(eol) {
+            let name = line.split(':').next().unwrap_or("");
+            match &self.edit {
+                HeaderEdit::Replace(header, value) if name == *header => lines.push(format!("{}: {}", name, value)),
+                HeaderEdit::Remove(headers) if headers.contains(&name) => {}
+                HeaderEdit::Duplicate { name: header, value, first } if name == *header => {
+                    let extra = format!("{}: {}", name, value);
+                    match first {
+                        true => lines.extend(vec![extra, line.to_string()]),
+                        false => lines.extend(vec![line.to_string(), extra]),
+                    }
+                }
+                _ => lines.push(line.to_string()),
+            }
+        }
+        format!("{}{}", lines.join(eol), body)
+    }
+}
+
+/// the signature with its first character changed, so it's well formed but doesn't verify
+fn corrupt(sig: &str) -> String {
+    let mut chars: Vec<char> = sig.chars().collect();
+    match chars.first_mut() {
+        Some(c) => *c = if *c == 'A' { 'B' } else { 'A' },
+        None => chars.push('A'),
+    }
+    chars.into_iter().collect()
+}
+
+/// one request that should be rejected
+struct Case {
+    name: &'static str,
+    path: &'static str,
+    expect: &'static [u16],
+    http_req: String,
+}
+
+/// result of one case
+struct Outcome {
+    name: &'static str,
+    path: &'static str,
+    /// status, or the error if no response was received
+    result: Result<u16, String>,
+    /// why the case failed, if it did
+    failure: Option<String>,
+}
+
+struct User {
+    user_id: Uuid,
+    key: fitbod::auth::PrivateKey,
+}
+
+fn random_workouts<R: Rng>(rng: &mut R, user_id: Uuid, n: usize) -> Vec<fitbod::Workout> {
+    (0..n).map(|_| {
+        // a random second in 2000, so that start times (unique per user) don't collide with
+        // existing workouts
+        let start_time = Utc.timestamp(rng.gen_range(946_684_800..978_307_200), 0);
+        let end_time = start_time + chrono::Duration::minutes(rng.gen_range(10..90));
+        fitbod::Workout { user_id, workout_id: Uuid::new_v4(), start_time, end_time }
+    }).collect()
+}
+
+/// every workout of `user`, via a correctly signed request
+fn list_all(client: &mut ApiClient, user: &User) -> Result<Vec<fitbod::Workout>, String> {
+    let req = ListWindow::default().request(user.user_id);
+    client.request(LIST, &req, &user.key)
+        .and_then(|body| http::decode::<fitbod::api::ListWorkoutsResponse>(&body[..]))
+        .map(|resp| resp.items)
+        .map_err(|e| format!("correctly signed list request for user id {} failed: {}", user.user_id, e))
+}
+
+/// builds every case for `path`, with `victim` as the user whose data is targeted and
+/// `other` as a second user
+fn cases<R: Rng>(rng: &mut R, tera: &tera::Tera, path: &'static str, victim: &User, other: &User, skew: Duration) -> Vec<Case> {
+    // a correctly signed request for `user_id`, which writes new workouts on /new
+    let mut valid = |user_id: Uuid, key: fitbod::auth::PrivateKey| -> Forgery {
+        match path {
+            NEW => Forgery::new(&fitbod::api::NewWorkoutsRequest { user_id, items: random_workouts(rng, user_id, 2) }, key),
+            _ => Forgery::new(&fitbod::api::ListWorkoutsRequest::from(user_id), key),
+        }
+    };
+    let skew = skew.as_secs() as i64;
+    let mut out = Vec::new();
+    let mut case = |name: &'static str, expect: &'static [u16], forgery: Forgery| {
+        out.push(Case { name, path, expect, http_req: forgery.render(tera, path) });
+    };
+
+    let x = valid(victim.user_id, victim.key);
+    let sig = corrupt(&x.signature());
+    case("corrupted signature", UNAUTHORIZED, Forgery { edit: HeaderEdit::Replace(SIGNATURE_HEADER, sig), ..x });
+
+    let x = valid(victim.user_id, victim.key);
+    case("garbage signature", UNAUTHORIZED, Forgery { edit: HeaderEdit::Replace(SIGNATURE_HEADER, "not-a-signature".to_string()), ..x });
+
+    // a request for the victim's workouts, correctly signed by the other user
+    case("user_id not matching the signing key", UNAUTHORIZED, valid(victim.user_id, other.key));
+
+    let x = valid(victim.user_id, victim.key);
+    case("missing signature header", BAD_AUTH_HEADERS, Forgery { edit: HeaderEdit::Remove(&[SIGNATURE_HEADER]), ..x });
+
+    let x = valid(victim.user_id, victim.key);
+    case("missing timestamp header", BAD_AUTH_HEADERS, Forgery { edit: HeaderEdit::Remove(&[TIMESTAMP_HEADER]), ..x });
+
+    let x = valid(victim.user_id, victim.key);
+    case("missing both auth headers", BAD_AUTH_HEADERS, Forgery { edit: HeaderEdit::Remove(&[SIGNATURE_HEADER, TIMESTAMP_HEADER]), ..x });
+
+    for &first in [true, false].iter() {
+        let x = valid(victim.user_id, victim.key);
+        let value = corrupt(&x.signature());
+        let name = if first { "duplicated signature header (bad one first)" } else { "duplicated signature header (bad one last)" };
+        case(name, BAD_AUTH_HEADERS, Forgery { edit: HeaderEdit::Duplicate { name: SIGNATURE_HEADER, value, first }, ..x });
+
+        let x = valid(victim.user_id, victim.key);
+        let value = (x.timestamp - skew).to_string();
+        let name = if first { "duplicated timestamp header (stale one first)" } else { "duplicated timestamp header (stale one last)" };
+        case(name, BAD_AUTH_HEADERS, Forgery { edit: HeaderEdit::Duplicate { name: TIMESTAMP_HEADER, value, first }, ..x });
+    }
+
+    let x = valid(victim.user_id, victim.key);
+    case("stale timestamp", UNAUTHORIZED, Forgery { timestamp: x.timestamp - skew, ..x });
+
+    let x = valid(victim.user_id, victim.key);
+    case("future timestamp", UNAUTHORIZED, Forgery { timestamp: x.timestamp + skew, ..x });
+
+    // signed, then a field of the body changed
+    let x = valid(victim.user_id, victim.key);
+    let sent = match path {
+        NEW => {
+            let mut req: fitbod::api::NewWorkoutsRequest = serde_json::from_str(&x.signed).unwrap();
+            req.items[0].end_time += chrono::Duration::minutes(1);
+            serde_json::to_string(&req).unwrap()
+        }
+        _ => {
+            let req = fitbod::api::ListWorkoutsRequest { limit: Some(1), ..victim.user_id.into() };
+            serde_json::to_string(&req).unwrap()
+        }
+    };
+    case("body altered after signing", UNAUTHORIZED, Forgery { sent, ..x });
+
+    // signed, then the user_id in the body changed to the other user's
+    let x = valid(victim.user_id, victim.key);
+    let sent = x.signed.replace(&victim.user_id.to_string(), &other.user_id.to_string());
+    case("user_id altered after signing", UNAUTHORIZED, Forgery { sent, ..x });
+
+    if path == NEW {
+        // correctly signed by the victim, but the workouts belong to the other user
+        let req = fitbod::api::NewWorkoutsRequest { user_id: victim.user_id, items: random_workouts(rng, other.user_id, 2) };
+        case("workouts of another user", FORBIDDEN, Forgery::new(&req, victim.key));
+    }
+    out
+}
+
+/// runs every case against both endpoints (see module docs). returns whether all passed.
+pub fn auth_test(users_csv_path: &Path, http: HttpOpts, metrics: MetricsOpts, skew: Duration) -> bool {
+    let mut rng = thread_rng();
+    let mut keys = crate::load_private_keys(users_csv_path);
+    assert!(keys.len() >= 2, "auth-test needs at least 2 users in {}", users_csv_path.display());
+    keys.shuffle(&mut rng);
+    let mut users = keys.iter().take(2).map(|x| User {
+        user_id: x.user_id,
+        key: crate::as_priv_key(base64::decode(&x.private_key).unwrap()),
+    });
+    let (victim, other) = (users.next().unwrap(), users.next().unwrap());
+    println!("victim user id {}, other user id {}", victim.user_id, other.user_id);
+
+    let metrics = MetricsSink::new(&metrics);
+    let mut client = ApiClient::new(&http, metrics.clone(), Default::default());
+    let mut tera = tera::Tera::default();
+    tera.add_raw_template("api-request", crate::API_REQUEST).unwrap();
+
+    // a correctly signed write and read for each user, which must succeed for the rejections
+    // to mean anything. the workouts written are then there to be leaked.
+    let mut seeded: Vec<Uuid> = Vec::new();
+    for user in [&victim, &other].iter() {
+        let req = fitbod::api::NewWorkoutsRequest { user_id: user.user_id, items: random_workouts(&mut rng, user.user_id, N_SEEDED) };
+        if let Err(e) = client.request(NEW, &req, &user.key) {
+            eprintln!("correctly signed write for user id {} failed: {}", user.user_id, e);
+            return false
+        }
+        seeded.extend(req.items.iter().map(|x| x.workout_id));
+    }
+    let before: Vec<Vec<fitbod::Workout>> = match [&victim, &other].iter().map(|user| list_all(&mut client, user)).collect() {
+        Ok(before) => before,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false
+        }
+    };
+    if let Some(workout_id) = seeded.iter().find(|id| ! before.iter().flatten().any(|x| x.workout_id == **id)) {
+        eprintln!("workout {} was written, but isn't listed", workout_id);
+        return false
+    }
+    println!("wrote {} workouts for each user (correctly signed requests work)", N_SEEDED);
+    let known: Vec<String> = before.iter().flatten().map(|x| x.workout_id.to_string()).collect();
+
+    let mut outcomes: Vec<Outcome> = Vec::new();
+    for &path in [LIST, NEW].iter() {
+        for Case { name, path, expect, http_req } in cases(&mut rng, &tera, path, &victim, &other, skew) {
+            let (result, failure) = match client.request_raw(path, &http_req) {
+                Ok((status, body)) => {
+                    let text = String::from_utf8_lossy(&body);
+                    let leaked = known.iter().filter(|x| text.contains(x.as_str())).count();
+                    let failure = if (200..300).contains(&status) {
+                        Some(format!("accepted (expected {:?})", expect))
+                    } else if leaked > 0 {
+                        Some(format!("rejection includes {} workouts", leaked))
+                    } else if ! expect.contains(&status) {
+                        Some(format!("wrong status (expected {:?})", expect))
+                    } else {
+                        None
+                    };
+                    if failure.is_some() {
+                        eprintln!("***\n{} ({}):\n\nREQUEST:\n\n{}\n\nRESPONSE ({}):\n\n{}\n", name, path, http_req, status, text);
+                    }
+                    (Ok(status), failure)
+                }
+                Err(e) => (Err(e.to_string()), Some("no response".to_string())),
+            };
+            outcomes.push(Outcome { name, path, result, failure });
+        }
+    }
+
+    for x in outcomes.iter() {
+        let result = match &x.result {
+            Ok(status) => status.to_string(),
+            Err(e) => e.clone(),
+        };
+        println!("  {:<4} {:<22} {:<48} {} {}", if x.failure.is_none() { "ok" } else { "FAIL" }, x.path, x.name, result,
+            x.failure.as_deref().unwrap_or(""));
+    }
+
+    // nothing the server rejected may have been stored, for either user
+    let mut n_mutated = 0;
+    for (user, before) in [&victim, &other].iter().zip(before.iter()) {
+        let after = match list_all(&mut client, user) {
+            Ok(after) => after,
+            Err(e) => {
+                // can't tell whether anything was stored, so it counts as changed
+                eprintln!("{}", e);
+                n_mutated += 1;
+                continue
+            }
+        };
+        let mismatches = oracle::diff(user.user_id, &oracle::expected(before.iter(), &ListWindow::default()), &after);
+        if ! mismatches.is_empty() {
+            eprintln!("workouts of user id {} changed ({} mismatches):\n{}", user.user_id, mismatches.len(), oracle::fmt_diff(&mismatches));
+            n_mutated += 1;
+        }
+    }
+    metrics.flush();
+
+    let n_failed = outcomes.iter().filter(|x| x.failure.is_some()).count();
+    println!("{} of {} requests handled correctly - workouts of {} of 2 users changed", outcomes.len() - n_failed, outcomes.len(), n_mutated);
+    n_failed == 0 && n_mutated == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forgery() -> Forgery {
+        let mut forgery = Forgery::new(&fitbod::api::ListWorkoutsRequest::from(Uuid::nil()), [0; 64]);
+        forgery.timestamp = 1_600_000_000;
+        forgery
+    }
+
+    /// renders `edit` against the real request template, returning the header lines (after
+    /// the request line) and everything after them
+    fn render(edit: HeaderEdit) -> (Vec<String>, String) {
+        let mut tera = tera::Tera::default();
+        tera.add_raw_template("api-request", crate::API_REQUEST).unwrap();
+        let mut forgery = forgery();
+        forgery.edit = edit;
+        let rendered = forgery.render(&tera, LIST);
+        let (head, body) = rendered.split_at(rendered.find("\r\n\r\n").expect("no blank line after the head"));
+        assert!( ! head.replace("\r\n", "").contains('\n'), "bare newline in head: {:?}", head);
+        let mut lines = head.split("\r\n").map(String::from);
+        assert_eq!(lines.next().unwrap(), format!("POST {} HTTP/1.1", LIST));
+        (lines.collect(), body.to_string())
+    }
+
+    fn auth_lines(lines: &[String]) -> Vec<&str> {
+        lines.iter().map(|x| x.as_str()).filter(|x| x.starts_with("x-fitbod-access-")).collect()
+    }
+
+    #[test]
+    fn unedited_request_is_left_alone() {
+        let (lines, body) = render(HeaderEdit::None);
+        assert_eq!(auth_lines(&lines), vec![
+            format!("{}: {}", SIGNATURE_HEADER, forgery().signature()),
+            format!("{}: 1600000000", TIMESTAMP_HEADER),
+        ]);
+        assert!(lines.contains(&"connection: close".to_string()));
+        assert_eq!(body, format!("\r\n\r\n{}", forgery().sent));
+    }
+
+    #[test]
+    fn replace_header() {
+        let (lines, body) = render(HeaderEdit::Replace(TIMESTAMP_HEADER, "soon".to_string()));
+        let (_, unedited) = render(HeaderEdit::None);
+        assert_eq!(auth_lines(&lines)[1], format!("{}: soon", TIMESTAMP_HEADER));
+        assert_eq!(lines.iter().filter(|x| x.starts_with(TIMESTAMP_HEADER)).count(), 1);
+        assert_eq!(body, unedited);
+    }
+
+    #[test]
+    fn remove_headers() {
+        let (all, _) = render(HeaderEdit::None);
+        let (lines, _) = render(HeaderEdit::Remove(&[SIGNATURE_HEADER]));
+        assert_eq!(auth_lines(&lines), vec![format!("{}: 1600000000", TIMESTAMP_HEADER)]);
+        assert_eq!(lines.len(), all.len() - 1);
+        let (lines, _) = render(HeaderEdit::Remove(&[SIGNATURE_HEADER, TIMESTAMP_HEADER]));
+        assert!(auth_lines(&lines).is_empty());
+        assert_eq!(lines.len(), all.len() - 2);
+    }
+
+    #[test]
+    fn duplicate_header() {
+        let original = format!("{}: 1600000000", TIMESTAMP_HEADER);
+        let extra = format!("{}: 1", TIMESTAMP_HEADER);
+        let (lines, _) = render(HeaderEdit::Duplicate { name: TIMESTAMP_HEADER, value: "1".to_string(), first: true });
+        assert_eq!(auth_lines(&lines)[1..], [extra.as_str(), original.as_str()]);
+        let (lines, _) = render(HeaderEdit::Duplicate { name: TIMESTAMP_HEADER, value: "1".to_string(), first: false });
+        assert_eq!(auth_lines(&lines)[1..], [original.as_str(), extra.as_str()]);
+    }
+
+    #[test]
+    fn corrupt_changes_only_the_first_character() {
+        assert_eq!(corrupt("Abc="), "Bbc=");
+        assert_eq!(corrupt("Bbc="), "Abc=");
+        assert_eq!(corrupt("xyz"), "Ayz");
+        assert_eq!(corrupt(""), "A");
+        let sig = "QUJDREVGRw==";
+        assert_ne!(corrupt(sig), sig);
+        assert_eq!(corrupt(sig).len(), sig.len());
+    }
+}
@@ -0,0 +1,318 @@
+//! compares two run reports (see `report`) to catch performance regressions between builds
+//!
+//! for each endpoint, throughput and latency percentiles of successful requests in the
+//! candidate run are compared to the baseline. a change is a regression when it is worse than
+//! the configured threshold *and* the difference is statistically significant according to a
+//! welch's t-test over the per-interval values of the two runs.
+//!
+//! the values compared against the thresholds are whole-run values (e.g. the p99 of every
+//! request in the run), while the t-test compares the means of the per-interval values (e.g.
+//! the mean of the per-second p99s), since the report only has percentiles, not histograms.
+//! the table labels the p-value column accordingly.
+
+use hashbrown::HashSet;
+use statrs::distribution::{ContinuousCDF, StudentsT};
+use structopt::StructOpt;
+
+use crate::report::{EndpointStats, IntervalStats, RunReport};
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct Thresholds {
+    /// fail if throughput of an endpoint drops by more than this percentage
+    #[structopt(long, default_value = "10")]
+    pub max_throughput_drop_pct: f64,
+
+    /// fail if a latency percentile of an endpoint increases by more than this percentage
+    #[structopt(long, default_value = "15")]
+    pub max_latency_increase_pct: f64,
+
+    /// fail if the share of requests to an endpoint that failed (non-2xx, timeouts, malformed
+    /// responses) increases by more than this many percentage points
+    #[structopt(long, default_value = "0.1")]
+    pub max_error_rate_increase: f64,
+
+    /// significance level for the t-test. a change that exceeds a threshold is only counted
+    /// as a regression if p < alpha
+    #[structopt(long, default_value = "0.05")]
+    pub alpha: f64,
+
+    /// ignore this many seconds at the start of each run when computing per-interval series
+    /// (e.g. to exclude warm-up)
+    #[structopt(long, default_value = "0")]
+    pub skip_secs: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Metric {
+    Throughput,
+    P50,
+    P90,
+    P99,
+    P99_9,
+}
+
+const METRICS: &[Metric] = &[Metric::Throughput, Metric::P50, Metric::P90, Metric::P99, Metric::P99_9];
+
+impl Metric {
+    fn name(&self) -> &'static str {
+        match self {
+            Metric::Throughput => "req/s",
+            Metric::P50 => "p50 ms",
+            Metric::P90 => "p90 ms",
+            Metric::P99 => "p99 ms",
+            Metric::P99_9 => "p99.9 ms",
+        }
+    }
+
+    fn latency(&self, x: &EndpointStats) -> f64 {
+        match self {
+            Metric::Throughput => unreachable!(),
+            Metric::P50 => x.p50_ms,
+            Metric::P90 => x.p90_ms,
+            Metric::P99 => x.p99_ms,
+            Metric::P99_9 => x.p99_9_ms,
+        }
+    }
+}
+
+fn is_success(status: &str) -> bool {
+    status.starts_with('2')
+}
+
+/// whole-run value of `metric` for successful requests to `endpoint`
+fn run_value(report: &RunReport, endpoint: &str, metric: Metric) -> Option<f64> {
+    let ok: Vec<&EndpointStats> = report.endpoints.iter()
+        .filter(|x| x.endpoint == endpoint && is_success(&x.status))
+        .collect();
+    match metric {
+        Metric::Throughput => {
+            let n: u64 = ok.iter().map(|x| x.count).sum();
+            Some(n as f64 / report.duration_secs)
+        }
+        // latency of the most common success status (e.g. 200 for list, 204 for new)
+        _ => ok.iter().max_by_key(|x| x.count).map(|x| metric.latency(x)),
+    }
+}
+
+/// per-interval values of `metric` for successful requests to `endpoint`
+fn interval_series(intervals: &[IntervalStats], endpoint: &str, metric: Metric) -> Vec<f64> {
+    intervals.iter()
+        .filter_map(|interval| {
+            let ok: Vec<&EndpointStats> = interval.endpoints.iter()
+                .filter(|x| x.endpoint == endpoint && is_success(&x.status))
+                .collect();
+            match metric {
+                Metric::Throughput => {
+                    let n: u64 = ok.iter().map(|x| x.count).sum();
+                    Some(n as f64 / interval.duration_secs)
+                }
+                _ => ok.iter().max_by_key(|x| x.count).map(|x| metric.latency(x)),
+            }
+        }).collect()
+}
+
+fn error_rate(report: &RunReport, endpoint: &str) -> f64 {
+    let (mut n, mut n_err) = (0u64, 0u64);
+    for x in report.endpoints.iter().filter(|x| x.endpoint == endpoint) {
+        n += x.count;
+        if ! is_success(&x.status) { n_err += x.count; }
+    }
+    if n == 0 { return 0.0 }
+    n_err as f64 / n as f64 * 100.0
+}
+
+fn mean_var(xs: &[f64]) -> (f64, f64) {
+    let n = xs.len() as f64;
+    let mean = xs.iter().sum::<f64>() / n;
+    let var = xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    (mean, var)
+}
+
+/// two-sided p-value of welch's t-test for a difference in means. `None` if either sample has
+/// fewer than two values.
+pub fn welch_t_test(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.len() < 2 || b.len() < 2 { return None }
+    match welch_t(a, b) {
+        Some((t, df)) => {
+            let dist = StudentsT::new(0.0, 1.0, df).expect("valid t distribution");
+            Some(2.0 * (1.0 - dist.cdf(t.abs())))
+        }
+        // both samples are constant
+        None => Some(if mean_var(a).0 == mean_var(b).0 { 1.0 } else { 0.0 }),
+    }
+}
+
+/// welch's t statistic and its degrees of freedom (welch-satterthwaite). `None` if neither
+/// sample varies. both samples must have at least two values.
+fn welch_t(a: &[f64], b: &[f64]) -> Option<(f64, f64)> {
+    let (mean_a, var_a) = mean_var(a);
+    let (mean_b, var_b) = mean_var(b);
+    let se_a = var_a / a.len() as f64;
+    let se_b = var_b / b.len() as f64;
+    if se_a + se_b == 0.0 { return None }
+    let t = (mean_a - mean_b) / (se_a + se_b).sqrt();
+    let df = (se_a + se_b).powi(2)
+        / (se_a.powi(2) / (a.len() as f64 - 1.0) + se_b.powi(2) / (b.len() as f64 - 1.0));
+    Some((t, df))
+}
+
+/// prints a comparison table and returns whether any regression was found
+pub fn compare_runs(baseline: &RunReport, candidate: &RunReport, thresholds: &Thresholds) -> bool {
+    let skip = |intervals: &[IntervalStats]| -> Vec<IntervalStats> {
+        intervals.iter().filter(|x| x.elapsed_secs > thresholds.skip_secs).cloned().collect()
+    };
+    let base_intervals = skip(&baseline.intervals);
+    let cand_intervals = skip(&candidate.intervals);
+
+    let mut endpoints: Vec<&str> = baseline.endpoints.iter()
+        .chain(candidate.endpoints.iter())
+        .map(|x| x.endpoint.as_str())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    endpoints.sort();
+
+    println!("baseline:  {} ({}, {:.0}s)", baseline.start, baseline.env.git_commit.as_deref().unwrap_or("unknown commit"), baseline.duration_secs);
+    println!("candidate: {} ({}, {:.0}s)", candidate.start, candidate.env.git_commit.as_deref().unwrap_or("unknown commit"), candidate.duration_secs);
+    println!();
+    println!("endpoint                 metric         baseline    candidate     delta p (intvl)  verdict");
+
+    let mut regressions: Vec<String> = Vec::new();
+    let mut any_untested = false;
+
+    for &endpoint in endpoints.iter() {
+        for &metric in METRICS {
+            let (base, cand) = match (run_value(baseline, endpoint, metric), run_value(candidate, endpoint, metric)) {
+                (Some(base), Some(cand)) => (base, cand),
+                (base, cand) => {
+                    println!("{:<24} {:<10} {:>12} {:>12}         -         -  missing", endpoint, metric.name(),
+                        base.map(|x| format!("{:.2}", x)).unwrap_or_else(|| "-".to_string()),
+                        cand.map(|x| format!("{:.2}", x)).unwrap_or_else(|| "-".to_string()),
+                    );
+                    continue
+                }
+            };
+
+            let delta_pct = if base == 0.0 { 0.0 } else { (cand - base) / base * 100.0 };
+            let p = welch_t_test(
+                &interval_series(&base_intervals, endpoint, metric),
+                &interval_series(&cand_intervals, endpoint, metric),
+            );
+            any_untested |= p.is_none();
+
+            let worse = match metric {
+                Metric::Throughput => -delta_pct > thresholds.max_throughput_drop_pct,
+                _ => delta_pct > thresholds.max_latency_increase_pct,
+            };
+            // without enough intervals to test, the threshold alone decides
+            let significant = p.map(|p| p < thresholds.alpha).unwrap_or(true);
+            let verdict = match (worse, significant) {
+                (true, true) => {
+                    regressions.push(format!("{} {} {:+.1}%", endpoint, metric.name(), delta_pct));
+                    "REGRESSION"
+                }
+                (true, false) => "worse (not significant)",
+                (false, _) => "ok",
+            };
+
+            println!("{:<24} {:<10} {:>12.2} {:>12.2} {:>8.1}% {:>9}  {}",
+                endpoint,
+                metric.name(),
+                base,
+                cand,
+                delta_pct,
+                p.map(|p| format!("{:.4}", p)).unwrap_or_else(|| "n/a".to_string()),
+                verdict,
+            );
+        }
+
+        let base_err = error_rate(baseline, endpoint);
+        let cand_err = error_rate(candidate, endpoint);
+        let worse = cand_err - base_err > thresholds.max_error_rate_increase;
+        if worse {
+            regressions.push(format!("{} error rate {:.3}% -> {:.3}%", endpoint, base_err, cand_err));
+        }
+        println!("{:<24} {:<10} {:>11.3}% {:>11.3}% {:>8.3}pp {:>9}  {}",
+            endpoint,
+            "errors",
+            base_err,
+            cand_err,
+            cand_err - base_err,
+            "-",
+            if worse { "REGRESSION" } else { "ok" },
+        );
+    }
+
+    println!("\nbaseline and candidate are whole-run values. p (intvl) is from a t-test of the per-interval values (e.g. mean of per-second p99s).");
+    if any_untested {
+        println!("note: p (intvl) is n/a where either run has fewer than two intervals with samples (thresholds alone applied)");
+    }
+
+    println!();
+    if regressions.is_empty() {
+        println!("no regressions");
+        false
+    } else {
+        println!("{} regression(s):", regressions.len());
+        for r in regressions.iter() {
+            println!("    {}", r);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // welch's t-test examples 1 and 2 from https://en.wikipedia.org/wiki/Welch%27s_t-test
+    const A1: &[f64] = &[27.5, 21.0, 19.0, 23.6, 17.0, 17.9, 16.9, 20.1, 21.9, 22.6, 23.1, 19.6, 19.0, 21.7, 21.4];
+    const B1: &[f64] = &[27.1, 22.0, 20.8, 23.4, 23.4, 23.5, 25.8, 22.0, 24.8, 20.2, 21.9, 22.1, 22.9, 20.5, 24.4];
+    const A2: &[f64] = &[17.2, 20.9, 22.6, 18.1, 21.7, 21.4, 23.5, 24.2, 14.7, 21.8];
+    const B2: &[f64] = &[21.5, 22.8, 21.0, 23.0, 21.6, 23.6, 22.5, 20.7, 23.4, 21.8, 20.7, 21.7, 21.5, 22.5, 23.6, 21.5, 22.5, 23.5, 21.5, 21.8];
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() < tolerance, "{} != {} (+/- {})", actual, expected, tolerance);
+    }
+
+    #[test]
+    fn t_and_df_match_known_values() {
+        let (t, df) = welch_t(A1, B1).unwrap();
+        assert_close(t, -2.4554, 1e-4);
+        assert_close(df, 24.9885, 1e-4);
+
+        let (t, df) = welch_t(A2, B2).unwrap();
+        assert_close(t, -1.5654, 1e-4);
+        assert_close(df, 9.9047, 1e-4);
+    }
+
+    #[test]
+    fn p_matches_known_values() {
+        assert_close(welch_t_test(A1, B1).unwrap(), 0.021, 1e-3);
+        assert_close(welch_t_test(A2, B2).unwrap(), 0.149, 1e-3);
+    }
+
+    #[test]
+    fn p_is_symmetric() {
+        assert_close(welch_t_test(A1, B1).unwrap(), welch_t_test(B1, A1).unwrap(), 1e-12);
+        let (t, _) = welch_t(B1, A1).unwrap();
+        assert_close(t, 2.4554, 1e-4);
+    }
+
+    #[test]
+    fn identical_samples_are_not_significant() {
+        assert_close(welch_t_test(A1, A1).unwrap(), 1.0, 1e-12);
+    }
+
+    #[test]
+    fn constant_samples() {
+        assert_eq!(welch_t_test(&[5.0, 5.0], &[5.0, 5.0, 5.0]), Some(1.0));
+        assert_eq!(welch_t_test(&[5.0, 5.0], &[6.0, 6.0]), Some(0.0));
+    }
+
+    #[test]
+    fn too_few_values() {
+        assert_eq!(welch_t_test(&[1.0], A1), None);
+        assert_eq!(welch_t_test(A1, &[]), None);
+    }
+}
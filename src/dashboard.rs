@@ -0,0 +1,459 @@
+//! full-screen terminal dashboard for stress-test (--tui)
+//!
+//! replaces the once-per-second log lines with a live view of the run, and lets the operator
+//! pause job dispatch or change the rate without restarting. anything that would otherwise be
+//! printed while the dashboard is up (failure dumps, slo notices) goes through `failure` /
+//! `notice`, which keep it in memory instead of garbling the screen, and print it once the
+//! dashboard is closed.
+
+use std::time::*;
+use std::io::{self, Stdout};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use chrono::prelude::*;
+use crossterm::{execute, terminal};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use pretty_toa::ThousandsSep;
+use tui::Terminal;
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{BarChart, Block, Borders, List, ListItem, Paragraph, Row, Sparkline, Table};
+
+use crate::load::Schedule;
+use crate::metrics::RunSnapshot;
+use crate::resources::{DbSample, ProcessSample};
+use crate::stats::{self, Latencies};
+
+/// number of failures kept for the recent failures pane (and printed after the dashboard closes)
+const MAX_RECENT_FAILURES: usize = 100;
+
+/// number of intervals shown in the latency sparklines
+const HISTORY_LEN: usize = 300;
+
+/// how much each rate key changes the rate by
+const RATE_STEP: f64 = 1.1;
+
+struct Failure {
+    at: DateTime<Local>,
+    summary: String,
+    /// full dump (e.g. request and response), printed after the dashboard closes
+    detail: Option<String>,
+}
+
+#[derive(Default)]
+struct Captured {
+    failures: VecDeque<Failure>,
+    n_failures: usize,
+    notices: Vec<String>,
+}
+
+/// output captured while the dashboard is shown. `None` otherwise.
+static CAPTURED: Mutex<Option<Captured>> = Mutex::new(None);
+
+/// reports a failed request. `detail` is printed to stderr right away, or, while the dashboard
+/// is shown, `summary` is added to its recent failures pane.
+pub fn failure(summary: String, detail: Option<String>) {
+    let mut captured = CAPTURED.lock().unwrap();
+    match captured.as_mut() {
+        Some(captured) => {
+            if captured.failures.len() == MAX_RECENT_FAILURES {
+                captured.failures.pop_front();
+            }
+            captured.failures.push_back(Failure { at: Local::now(), summary, detail });
+            captured.n_failures += 1;
+        }
+
+        None => {
+            if let Some(detail) = detail {
+                eprintln!("{}", detail);
+            }
+        }
+    }
+}
+
+/// prints `msg`, or, while the dashboard is shown, shows it in the status line
+pub fn notice(msg: String) {
+    let mut captured = CAPTURED.lock().unwrap();
+    match captured.as_mut() {
+        Some(captured) => captured.notices.push(msg),
+        None => println!("{}", msg),
+    }
+}
+
+/// action requested from the keyboard
+pub enum Command {
+    TogglePause,
+    /// multiply the rate by this
+    ScaleRate(f64),
+    Stop,
+}
+
+/// what the manager loop has to show at the end of each interval
+pub struct Tick<'a> {
+    pub elapsed: Duration,
+    pub interval: Duration,
+    /// latencies recorded during the interval
+    pub latencies: &'a Latencies,
+    pub snapshot: RunSnapshot,
+    pub harness_cpu_pct: f64,
+    pub queue_depths: Vec<usize>,
+    pub queue_capacity: usize,
+    pub schedule: Option<&'a Schedule>,
+    pub server: Option<&'a ProcessSample>,
+    /// only set on intervals when postgres was sampled
+    pub db: Option<&'a DbSample>,
+}
+
+struct EndpointRow {
+    endpoint: String,
+    req_per_sec: f64,
+    ok_per_sec: f64,
+    n_errors: u64,
+    p50_us: u64,
+    p99_us: u64,
+    p99_9_us: u64,
+    max_us: u64,
+}
+
+pub struct Dashboard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    /// reinstates the panic hook that was set before the dashboard's own
+    restore_panic_hook: Option<Box<dyn FnOnce()>>,
+    paused: bool,
+    /// open-loop mode only
+    rate_control: bool,
+    elapsed: Duration,
+    req_per_sec: f64,
+    harness_cpu_pct: f64,
+    endpoints: Vec<EndpointRow>,
+    snapshot: Option<RunSnapshot>,
+    schedule_info: Option<String>,
+    server_info: Option<String>,
+    db_info: Option<String>,
+    /// per-interval p50 and p99 of all requests, in microseconds
+    p50_history: VecDeque<u64>,
+    p99_history: VecDeque<u64>,
+    /// number of workers with each queue depth (index)
+    workers_by_depth: Vec<u64>,
+}
+
+impl Dashboard {
+    /// switches the terminal to the dashboard. `rate_control` is whether the rate keys do
+    /// anything (i.e. the run has a --rate or --profile).
+    pub fn new(rate_control: bool) -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, terminal::EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+        terminal.hide_cursor()?;
+        terminal.clear()?;
+
+        // a panic would otherwise leave the terminal in raw mode, with the message printed to
+        // the alternate screen (and lost)
+        let prev_hook = Arc::new(std::panic::take_hook());
+        let hook = Arc::clone(&prev_hook);
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            hook(info);
+        }));
+        let restore_panic_hook: Box<dyn FnOnce()> = Box::new(move || {
+            std::panic::set_hook(Box::new(move |info| prev_hook(info)));
+        });
+
+        *CAPTURED.lock().unwrap() = Some(Captured::default());
+
+        Ok(Self {
+            terminal,
+            restore_panic_hook: Some(restore_panic_hook),
+            paused: false,
+            rate_control,
+            elapsed: Duration::from_secs(0),
+            req_per_sec: 0.0,
+            harness_cpu_pct: 0.0,
+            endpoints: Vec::new(),
+            snapshot: None,
+            schedule_info: None,
+            server_info: None,
+            db_info: None,
+            p50_history: VecDeque::with_capacity(HISTORY_LEN),
+            p99_history: VecDeque::with_capacity(HISTORY_LEN),
+            workers_by_depth: Vec::new(),
+        })
+    }
+
+    /// returns the first command entered since the last call, without blocking
+    pub fn poll_input(&mut self) -> Option<Command> {
+        while event::poll(Duration::from_secs(0)).unwrap_or(false) {
+            let cmd = match event::read() {
+                Ok(Event::Key(KeyEvent { code, modifiers, .. })) => match code {
+                    KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => Some(Command::Stop),
+                    KeyCode::Char('q') | KeyCode::Esc => Some(Command::Stop),
+                    KeyCode::Char('p') | KeyCode::Char(' ') => Some(Command::TogglePause),
+                    KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Up => Some(Command::ScaleRate(RATE_STEP)),
+                    KeyCode::Char('-') | KeyCode::Down => Some(Command::ScaleRate(1.0 / RATE_STEP)),
+                    _ => None,
+                }
+
+                Ok(Event::Resize(..)) => {
+                    self.draw();
+                    None
+                }
+
+                _ => None,
+            };
+            if cmd.is_some() { return cmd }
+        }
+        None
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.draw();
+    }
+
+    /// updates the dashboard with the stats of the interval that just ended, and redraws it
+    pub fn update(&mut self, tick: Tick) {
+        let secs = tick.interval.as_secs_f64();
+        let mut endpoints: Vec<&str> = tick.latencies.iter().map(|(endpoint, _, _)| endpoint).collect();
+        endpoints.dedup();
+        self.endpoints = endpoints.into_iter()
+            .filter_map(|endpoint| {
+                let h = tick.latencies.combined(Some(endpoint))?;
+                let (n, n_ok) = tick.latencies.counts(Some(endpoint));
+                Some(EndpointRow {
+                    endpoint: endpoint.to_string(),
+                    req_per_sec: n as f64 / secs,
+                    ok_per_sec: n_ok as f64 / secs,
+                    n_errors: n - n_ok,
+                    p50_us: h.value_at_quantile(0.5),
+                    p99_us: h.value_at_quantile(0.99),
+                    p99_9_us: h.value_at_quantile(0.999),
+                    max_us: h.max(),
+                })
+            }).collect();
+        self.req_per_sec = tick.latencies.counts(None).0 as f64 / secs;
+
+        let (p50, p99) = tick.latencies.combined(None)
+            .map(|h| (h.value_at_quantile(0.5), h.value_at_quantile(0.99)))
+            .unwrap_or((0, 0));
+        for (history, x) in [(&mut self.p50_history, p50), (&mut self.p99_history, p99)] {
+            if history.len() == HISTORY_LEN { history.pop_front(); }
+            history.push_back(x);
+        }
+
+        self.workers_by_depth = vec![0; tick.queue_capacity + 1];
+        for depth in tick.queue_depths {
+            self.workers_by_depth[depth.min(tick.queue_capacity)] += 1;
+        }
+
+        self.schedule_info = tick.schedule.map(|schedule| {
+            format!("target {:.0} req/s (x{:.2}, stage {}) - backlog {} - lag {:?} - {} dropped",
+                schedule.target_rate(),
+                schedule.rate_scale(),
+                schedule.current_stage().map(|x| x.to_string()).unwrap_or_else(|| "-".to_string()),
+                schedule.backlog().thousands_sep(),
+                schedule.lag(),
+                schedule.n_dropped.thousands_sep(),
+            )
+        });
+        self.server_info = tick.server.map(|x| x.fmt_line());
+        if let Some(db) = tick.db {
+            self.db_info = Some(db.fmt_line());
+        }
+        self.elapsed = tick.elapsed;
+        self.harness_cpu_pct = tick.harness_cpu_pct;
+        self.snapshot = Some(tick.snapshot);
+        self.draw();
+    }
+
+    /// restores the terminal, then prints the failures and notices captured while the
+    /// dashboard was shown
+    pub fn close(self) {
+        drop(self);
+        let captured = CAPTURED.lock().unwrap().take().unwrap_or_default();
+        for msg in captured.notices.iter() {
+            println!("{}", msg);
+        }
+        if captured.n_failures > 0 {
+            eprintln!("{} failures while the dashboard was shown; most recent {}:", captured.n_failures.thousands_sep(), captured.failures.len());
+            for Failure { at, summary, detail } in captured.failures.iter() {
+                eprintln!("{} {}", at.format("%H:%M:%S"), summary);
+                if let Some(detail) = detail {
+                    eprintln!("{}", detail);
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self) {
+        // copied out, so that workers reporting failures aren't blocked while the screen is drawn
+        let (failures, n_failures, last_notice) = match CAPTURED.lock().unwrap().as_ref() {
+            Some(c) => {
+                let failures: Vec<String> = c.failures.iter().rev()
+                    .map(|x| format!("{} {}", x.at.format("%H:%M:%S"), x.summary))
+                    .collect();
+                (failures, c.n_failures, c.notices.last().cloned())
+            }
+            None => return,
+        };
+
+        let header = Spans::from(vec![
+            Span::styled("fitbod-test stress-test", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(format!("  elapsed {}  {:.0} req/s  harness cpu {:.0}%  ",
+                humantime::format_duration(Duration::from_secs(self.elapsed.as_secs())),
+                self.req_per_sec,
+                self.harness_cpu_pct,
+            )),
+            if self.paused {
+                Span::styled("PAUSED", Style::default().fg(Color::Black).bg(Color::Yellow))
+            } else {
+                Span::styled("running", Style::default().fg(Color::Green))
+            },
+        ]);
+        let mut header_lines = vec![
+            header,
+            Spans::from(self.schedule_info.clone().unwrap_or_else(|| "closed-loop mode".to_string())),
+        ];
+        header_lines.extend(self.server_info.iter().chain(self.db_info.iter()).map(|x| Spans::from(x.clone())));
+        let header_height = header_lines.len() as u16 + 1;
+        let keys = Spans::from(vec![
+            Span::styled(
+                format!("[p/space] pause  {}[q] stop", if self.rate_control { "[+/-] rate  " } else { "" }),
+                Style::default().fg(Color::DarkGray),
+            ),
+            Span::raw(last_notice.map(|x| format!("  {}", x)).unwrap_or_default()),
+        ]);
+
+        let endpoint_rows: Vec<Row> = self.endpoints.iter()
+            .map(|x| Row::new(vec![
+                x.endpoint.clone(),
+                format!("{:.0}", x.req_per_sec),
+                format!("{:.0}", x.ok_per_sec),
+                x.n_errors.to_string(),
+                stats::fmt_us(x.p50_us),
+                stats::fmt_us(x.p99_us),
+                stats::fmt_us(x.p99_9_us),
+                stats::fmt_us(x.max_us),
+            ])).collect();
+        let endpoint_table = Table::new(endpoint_rows)
+            .header(Row::new(vec!["endpoint", "req/s", "ok/s", "errors", "p50", "p99", "p99.9", "max"])
+                .style(Style::default().add_modifier(Modifier::BOLD)))
+            .widths(&[
+                Constraint::Min(24),
+                Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Length(7),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Length(10),
+            ])
+            .block(Block::default().borders(Borders::ALL).title("last interval"));
+
+        let counter_lines: Vec<Spans> = match self.snapshot.as_ref() {
+            Some(x) => vec![
+                Spans::from(format!("reads              {}", x.n_read.thousands_sep())),
+                Spans::from(format!("writes             {}", x.n_write.thousands_sep())),
+                Spans::from(format!("inserted (pending) {}", x.workouts_pending.thousands_sep())),
+                Spans::from(format!("inserted (conf.)   {}", x.workouts_confirmed.thousands_sep())),
+                Spans::from(format!("errors             {}", x.n_errors.thousands_sep())),
+                Spans::from(format!("timeouts           {}", x.n_timeouts.thousands_sep())),
+                Spans::from(format!("malformed          {}", x.n_malformed.thousands_sep())),
+                Spans::from(format!("verification fails {}", x.n_verification_failures.thousands_sep())),
+            ],
+            None => vec![Spans::from("waiting for first interval")],
+        };
+        let counters = Paragraph::new(counter_lines)
+            .block(Block::default().borders(Borders::ALL).title("totals"));
+
+        let p50: Vec<u64> = self.p50_history.iter().cloned().collect();
+        let p99: Vec<u64> = self.p99_history.iter().cloned().collect();
+        let p50_title = format!("p50 (all endpoints) - {}", stats::fmt_us(p50.last().cloned().unwrap_or(0)));
+        let p99_title = format!("p99 (all endpoints) - {}", stats::fmt_us(p99.last().cloned().unwrap_or(0)));
+
+        let depth_labels: Vec<String> = (0..self.workers_by_depth.len()).map(|x| x.to_string()).collect();
+        let depth_data: Vec<(&str, u64)> = depth_labels.iter()
+            .map(|x| x.as_str())
+            .zip(self.workers_by_depth.iter().cloned())
+            .collect();
+        let queue_depths = BarChart::default()
+            .data(&depth_data)
+            .bar_width(5)
+            .bar_style(Style::default().fg(Color::Cyan))
+            .block(Block::default().borders(Borders::ALL).title("workers by queue depth"));
+
+        let failure_items: Vec<ListItem> = failures.into_iter().map(ListItem::new).collect();
+        let failure_list = List::new(failure_items)
+            .style(Style::default().fg(Color::Red))
+            .block(Block::default().borders(Borders::ALL).title(format!("recent failures ({} total)", n_failures.thousands_sep())));
+
+        let res = self.terminal.draw(|f| {
+            let rows = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([
+                    Constraint::Length(header_height),
+                    Constraint::Length(9),
+                    Constraint::Length(6),
+                    Constraint::Min(6),
+                    Constraint::Length(1),
+                ])
+                .split(f.size());
+
+            f.render_widget(Paragraph::new(header_lines).block(Block::default().borders(Borders::BOTTOM)), rows[0]);
+
+            let top = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(40), Constraint::Length(34)])
+                .split(rows[1]);
+            f.render_widget(endpoint_table, top[0]);
+            f.render_widget(counters, top[1]);
+
+            let sparklines = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .split(rows[2]);
+            // show the most recent values that fit
+            let fit = |xs: &[u64], width: u16| xs.len().saturating_sub(width.saturating_sub(2) as usize);
+            f.render_widget(Sparkline::default()
+                .data(&p50[fit(&p50, sparklines[0].width)..])
+                .style(Style::default().fg(Color::Green))
+                .block(Block::default().borders(Borders::ALL).title(p50_title)), sparklines[0]);
+            f.render_widget(Sparkline::default()
+                .data(&p99[fit(&p99, sparklines[1].width)..])
+                .style(Style::default().fg(Color::Yellow))
+                .block(Block::default().borders(Borders::ALL).title(p99_title)), sparklines[1]);
+
+            let bottom = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Length(depth_data.len() as u16 * 6 + 2), Constraint::Min(20)])
+                .split(rows[3]);
+            f.render_widget(queue_depths, bottom[0]);
+            f.render_widget(failure_list, bottom[1]);
+
+            f.render_widget(Paragraph::new(keys), rows[4]);
+        });
+        if let Err(e) = res {
+            notice(format!("failed to draw dashboard: {}", e));
+        }
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        let _ = self.terminal.show_cursor();
+        restore_terminal();
+        // (the hook can't be replaced while panicking, and the terminal is restored already)
+        if let Some(restore_panic_hook) = self.restore_panic_hook.take() {
+            if ! std::thread::panicking() {
+                restore_panic_hook();
+            }
+        }
+    }
+}
+
+fn restore_terminal() {
+    let _ = terminal::disable_raw_mode();
+    let _ = execute!(io::stdout(), terminal::LeaveAlternateScreen);
+}
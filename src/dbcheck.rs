@@ -0,0 +1,74 @@
+//! cross-check of the final state against postgres (--verify-db)
+//!
+//! the final check reads every user back through /api/v1/workouts/list, which is served by
+//! the code under test (possibly from a cache in front of the database). with --verify-db,
+//! each user's rows in the workouts table are also compared to the workouts written, and to
+//! what the final check's list request returned, so a server that serves writes it never
+//! stored (or stores writes it doesn't serve) is caught.
+
+use chrono::{DateTime, Utc};
+use hashbrown::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::oracle::{self, Mismatch};
+use crate::resources::WORKOUTS_TABLE;
+
+/// user ids per query
+const CHUNK_SIZE: usize = 1000;
+
+/// every row in the workouts table for `user_ids`, in `oracle::LIST_ORDER` (users without any
+/// rows map to an empty list)
+pub fn load_workouts(db_url: &str, user_ids: &[Uuid]) -> HashMap<Uuid, Vec<fitbod::Workout>> {
+    let mut out: HashMap<Uuid, Vec<fitbod::Workout>> = user_ids.iter().map(|x| (*x, Vec::new())).collect();
+    let query = format!(
+        "select user_id, workout_id, start_time, end_time from {} \
+         where user_id = any($1) order by user_id, start_time desc, workout_id", WORKOUTS_TABLE);
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        let db = fitbod::db::DataBase::new(db_url).await
+            .unwrap_or_else(|e| panic!("--verify-db failed to connect to DATABASE_URL: {}", e));
+        for chunk in user_ids.chunks(CHUNK_SIZE) {
+            let rows: Vec<(Uuid, Uuid, DateTime<Utc>, DateTime<Utc>)> = sqlx::query_as(&query)
+                .bind(chunk)
+                .fetch_all(db.pool())
+                .await
+                .unwrap_or_else(|e| panic!("--verify-db query failed: {}", e));
+            for (user_id, workout_id, start_time, end_time) in rows {
+                out.entry(user_id).or_default().push(fitbod::Workout { user_id, workout_id, start_time, end_time });
+            }
+        }
+    });
+    out
+}
+
+/// compares `rows` (a user's stored workouts, from `load_workouts`) to `expected`. row order
+/// doesn't mean anything, so it isn't checked.
+pub fn diff_stored(user_id: Uuid, expected: &[fitbod::Workout], rows: &[fitbod::Workout]) -> Vec<Mismatch> {
+    oracle::diff(user_id, expected, rows).into_iter()
+        .filter(|x| ! matches!(x, Mismatch::OutOfOrder { .. }))
+        .collect()
+}
+
+/// compares what the api returned for a user (`items`, from an unfiltered list request) to
+/// their stored `rows`. stored workouts beyond the end of the response aren't counted, since
+/// the server may cap the number of workouts it returns.
+pub fn diff_served(user_id: Uuid, rows: &[fitbod::Workout], items: &[fitbod::Workout]) -> Vec<Mismatch> {
+    oracle::diff(user_id, rows, items).into_iter()
+        .filter(|x| ! matches!(x, Mismatch::Missing { pos, .. } if *pos >= items.len()))
+        .collect()
+}
+
+/// missing, extra and mutated rows among `mismatches` (from `diff_stored`)
+pub fn count_rows(mismatches: &[Mismatch]) -> (usize, usize, usize) {
+    let (mut missing, mut extra) = (0, 0);
+    let mut mutated: HashSet<Uuid> = HashSet::new();
+    for mismatch in mismatches {
+        match mismatch {
+            Mismatch::Missing { .. } => missing += 1,
+            Mismatch::Unexpected { .. } | Mismatch::OtherUser { .. } | Mismatch::Duplicate { .. } => extra += 1,
+            Mismatch::Field { workout_id, .. } => { mutated.insert(*workout_id); }
+            Mismatch::OutOfOrder { .. } => {}
+        }
+    }
+    (missing, extra, mutated.len())
+}
@@ -0,0 +1,311 @@
+//! stress-test workers: the code that executes the jobs assigned by the manager loop
+//!
+//! two engines are available. `Threads` runs each worker on its own os thread, sending
+//! one request at a time with blocking i/o. `Async` runs each worker (virtual user) as a
+//! tokio task, so the number of concurrent connections is no longer tied to thread count.
+
+use std::time::*;
+use std::sync::{Arc, atomic::Ordering};
+use std::str::FromStr;
+use std::fmt;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{Counters, Inserted, StressTestJob};
+use crate::oracle::{self, ListWindow};
+use crate::history::{History, WriteOutcome};
+use crate::http::{self, ApiClient, ApiError, AsyncApiClient};
+use crate::dashboard;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Threads,
+    Async,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threads" => Ok(Engine::Threads),
+            "async" => Ok(Engine::Async),
+            other => Err(format!("invalid engine '{}' (expected threads or async)", other)),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Engine::Threads => f.write_str("threads"),
+            Engine::Async => f.write_str("async"),
+        }
+    }
+}
+
+/// channel used by the manager to hand jobs to a worker
+pub enum JobSender {
+    Thread(crossbeam_channel::Sender<StressTestJob>),
+    Task(tokio::sync::mpsc::Sender<StressTestJob>),
+}
+
+impl JobSender {
+    /// returns the job back if the worker's queue is full
+    pub fn try_send(&self, job: StressTestJob) -> Option<StressTestJob> {
+        match self {
+            JobSender::Thread(tx) => match tx.try_send(job) {
+                Ok(_) => None,
+                Err(crossbeam_channel::TrySendError::Full(j)) => Some(j),
+                Err(crossbeam_channel::TrySendError::Disconnected(_)) => panic!("thread rx disconnected"),
+            }
+
+            JobSender::Task(tx) => match tx.try_send(job) {
+                Ok(_) => None,
+                Err(tokio::sync::mpsc::error::TrySendError::Full(j)) => Some(j),
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => panic!("task rx disconnected"),
+            }
+        }
+    }
+
+    /// number of jobs waiting in the worker's queue
+    pub fn queue_len(&self) -> usize {
+        match self {
+            JobSender::Thread(tx) => tx.len(),
+            JobSender::Task(tx) => tx.max_capacity() - tx.capacity(),
+        }
+    }
+
+    pub fn queue_capacity(&self) -> usize {
+        match self {
+            JobSender::Thread(tx) => tx.capacity().unwrap_or(0),
+            JobSender::Task(tx) => tx.max_capacity(),
+        }
+    }
+
+    /// blocks until the job is accepted. must not be called from inside the tokio runtime.
+    pub fn send(&self, job: StressTestJob) {
+        match self {
+            JobSender::Thread(tx) => tx.send(job).unwrap(),
+            JobSender::Task(tx) => {
+                if tx.blocking_send(job).is_err() { panic!("task rx disconnected") }
+            }
+        }
+    }
+}
+
+/// worker loop for the `Threads` engine. see `Job` for when the user's lock is held.
+pub fn worker_thread(
+    rx: crossbeam_channel::Receiver<StressTestJob>,
+    mut client: ApiClient,
+    counters: Arc<Counters>,
+    read_only: bool,
+    unlocked: bool,
+    history: Option<Arc<History>>,
+) {
+    loop {
+        let job = match rx.recv() {
+            Ok(job) => match Job::new(job, read_only, unlocked) {
+                Some(job) => job,
+                None => break,
+            },
+            Err(e) => panic!("worker rx failed: {}", e),
+        };
+        let inserted = Arc::clone(&job.inserted);
+        let mut lock = match job.lock_during_request() {
+            true => Some(inserted.blocking_write()),
+            false => None,
+        };
+        let resp = client.request_scheduled(job.path(), &job.req, &job.key, job.scheduled);
+        if job.lock_after_request() {
+            lock = Some(inserted.blocking_write());
+        }
+        job.finish(resp, lock, &counters, history.as_deref());
+    }
+}
+
+/// worker loop for the `Async` engine. each virtual user sends one request at a time.
+/// locking is the same as `worker_thread`.
+pub async fn virtual_user(
+    mut rx: tokio::sync::mpsc::Receiver<StressTestJob>,
+    mut client: AsyncApiClient,
+    counters: Arc<Counters>,
+    read_only: bool,
+    unlocked: bool,
+    history: Option<Arc<History>>,
+) {
+    while let Some(job) = rx.recv().await {
+        let job = match Job::new(job, read_only, unlocked) {
+            Some(job) => job,
+            None => break,
+        };
+        let inserted = Arc::clone(&job.inserted);
+        let mut lock = match job.lock_during_request() {
+            true => Some(inserted.write().await),
+            false => None,
+        };
+        let resp = client.request(job.path(), &job.req, &job.key, job.scheduled).await;
+        if job.lock_after_request() {
+            lock = Some(inserted.write().await);
+        }
+        job.finish(resp, lock, &counters, history.as_deref());
+    }
+}
+
+/// body of a list or new request
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Request {
+    List(fitbod::api::ListWorkoutsRequest),
+    New(fitbod::api::NewWorkoutsRequest),
+}
+
+/// a read or write job, as executed by either engine. the engines differ only in how they
+/// send the request and wait for the user's `inserted` lock; when to take the lock is
+/// decided here.
+///
+/// by default, the lock is held for the duration of each request, so requests for the same
+/// user never overlap and each read can be checked against exact expected state. when
+/// `unlocked`, it is only taken to record the outcome of a write, and reads are checked
+/// afterwards, from the history. in --read-only mode, reads don't take it either.
+struct Job {
+    user_id: Uuid,
+    /// `None` for writes
+    window: Option<ListWindow>,
+    req: Request,
+    key: fitbod::auth::PrivateKey,
+    inserted: Arc<tokio::sync::RwLock<Inserted>>,
+    scheduled: Option<Instant>,
+    invoked: Instant,
+    read_only: bool,
+    unlocked: bool,
+}
+
+impl Job {
+    /// `None` for `StressTestJob::Exit`
+    fn new(job: StressTestJob, read_only: bool, unlocked: bool) -> Option<Self> {
+        let (user_id, window, req, key, inserted, scheduled) = match job {
+            StressTestJob::Exit => return None,
+
+            StressTestJob::Read { user_id, window, key, inserted, scheduled } => {
+                let req = Request::List(window.request(user_id));
+                (user_id, Some(window), req, key, inserted, scheduled)
+            }
+
+            StressTestJob::Write { user_id, key, workouts, inserted, scheduled } => {
+                assert!( ! read_only );
+                let req = Request::New(fitbod::api::NewWorkoutsRequest { user_id, items: workouts });
+                (user_id, None, req, key, inserted, scheduled)
+            }
+        };
+        Some(Job { user_id, window, req, key, inserted, scheduled, invoked: Instant::now(), read_only, unlocked })
+    }
+
+    fn path(&self) -> &'static str {
+        match self.req {
+            Request::List(_) => "/api/v1/workouts/list",
+            Request::New(_) => "/api/v1/workouts/new",
+        }
+    }
+
+    /// whether the lock must be taken before sending the request
+    fn lock_during_request(&self) -> bool {
+        match self.req {
+            Request::List(_) => ! (self.read_only || self.unlocked),
+            Request::New(_) => ! self.unlocked,
+        }
+    }
+
+    /// whether the lock must be taken once the response is in (to record a write's outcome)
+    fn lock_after_request(&self) -> bool {
+        matches!(self.req, Request::New(_)) && ! self.lock_during_request()
+    }
+
+    /// checks and records the response. `lock` is the user's lock if either `lock_during_request`
+    /// or `lock_after_request` said to take it, and is released before the history is recorded.
+    fn finish(
+        self,
+        resp: Result<Vec<u8>, ApiError>,
+        mut lock: Option<tokio::sync::RwLockWriteGuard<'_, Inserted>>,
+        counters: &Counters,
+        history: Option<&History>,
+    ) {
+        match self.req {
+            Request::List(_) => {
+                let window = self.window.expect("read jobs have a window");
+                let returned = on_list_response(self.user_id, &window, resp, lock.as_deref_mut(), counters);
+                drop(lock);
+                if let Some(history) = history {
+                    history.read(self.user_id, &window, self.invoked, returned);
+                }
+            }
+
+            Request::New(req) => {
+                let outcome = WriteOutcome::of(&resp);
+                on_new_response(&req.items[..], resp, &mut lock.expect("writes hold the lock"), counters);
+                if let Some(history) = history {
+                    history.write(self.user_id, &req.items[..], self.invoked, outcome);
+                }
+            }
+        }
+    }
+}
+
+/// handles the response to a read job. `expected` is `None` in --read-only and --unlocked
+/// mode, in which case the response is only checked for being well-formed. returns the
+/// `workout_id`s returned, if the request succeeded.
+fn on_list_response(
+    user_id: Uuid,
+    window: &ListWindow,
+    resp: Result<Vec<u8>, ApiError>,
+    expected: Option<&mut Inserted>,
+    counters: &Counters,
+) -> Option<Vec<Uuid>> {
+    let resp = resp.and_then(|body| http::decode::<fitbod::api::ListWorkoutsResponse>(&body[..]));
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            if let ApiError::Decode(msg) = &e {
+                dashboard::failure(format!("invalid list response for user id {}: {}", user_id, msg), None);
+            }
+            counters.record_failure("/api/v1/workouts/list", &e);
+            return None
+        }
+    };
+
+    if let Some(expected) = expected {
+        match expected.verify(user_id, &resp.items[..], window) {
+            Ok(n_observed) => { counters.n_inserted.fetch_add(n_observed, Ordering::Relaxed); }
+            Err(mismatches) => {
+                dashboard::failure(format!("read check failed for user id {}: {}", user_id, mismatches[0]), Some(format!("read check failed for user id {} ({} mismatches, {:?}):\n{}",
+                    user_id, mismatches.len(), window, oracle::fmt_diff(&mismatches),
+                )));
+                counters.record_verification_failure(&mismatches);
+            }
+        }
+    }
+    Some(resp.items.iter().map(|x| x.workout_id).collect())
+}
+
+/// handles the response to a write job
+fn on_new_response(
+    items: &[fitbod::Workout],
+    resp: Result<Vec<u8>, ApiError>,
+    inserted: &mut Inserted,
+    counters: &Counters,
+) {
+    match resp {
+        Ok(_resp) => {
+            let n_new = inserted.confirm(items);
+            counters.n_inserted.fetch_add(n_new, Ordering::Relaxed);
+        }
+
+        Err(e) => {
+            // the workouts may show up in later list responses, which is checked for then
+            if e.may_have_been_processed() {
+                inserted.maybe_written(items);
+            }
+            counters.record_failure("/api/v1/workouts/new", &e);
+        }
+    }
+}
@@ -0,0 +1,475 @@
+//! history of the requests made during a stress-test run, and an offline consistency check
+//! over it
+//!
+//! every read and write is recorded (one json object per line) with the times it was
+//! invoked and completed, relative to the start of the run. since workouts are only ever
+//! added, each user's workouts behave like a grow-only set, and `check` tests the real-time
+//! conditions a linearizable server must meet for one:
+//!
+//! - no phantom reads: a read never returns a workout that was never written, or whose
+//!   write was invoked after the read completed
+//! - a read never returns a workout outside its window
+//! - read-your-writes: a read returns every workout (in its window) whose write completed
+//!   before the read was invoked
+//! - monotonic reads: a read returns every workout (in its window) that another read
+//!   returned before it was invoked
+//!
+//! this doesn't require a harness-side lock around requests, so it still holds when reads
+//! and writes for the same user run concurrently (see --unlocked).
+
+use std::time::*;
+use std::path::Path;
+use std::io::{self, prelude::*, BufWriter};
+use std::fs::File;
+use std::sync::Mutex;
+use std::fmt;
+use chrono::{DateTime, Utc};
+use hashbrown::{HashMap, HashSet};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::http::ApiError;
+use crate::oracle::ListWindow;
+
+/// one request, recorded when it completes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Op {
+    pub user_id: Uuid,
+    /// microseconds since the start of the run
+    pub invoke_us: u64,
+    pub complete_us: u64,
+    #[serde(flatten)]
+    pub kind: OpKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OpKind {
+    Write {
+        /// (workout_id, start_time) of each workout sent
+        items: Vec<(Uuid, DateTime<Utc>)>,
+        outcome: WriteOutcome,
+    },
+
+    Read {
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        limit: Option<usize>,
+        /// `workout_id`s returned, in order. `None` if the request failed.
+        returned: Option<Vec<Uuid>>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteOutcome {
+    Ok,
+    /// the request failed in a way that rules out the server having applied it
+    Failed,
+    /// the request failed, but may have been applied (e.g. a read timeout)
+    Unknown,
+}
+
+impl WriteOutcome {
+    pub fn of<T>(resp: &Result<T, ApiError>) -> Self {
+        match resp {
+            Ok(_) => WriteOutcome::Ok,
+            Err(e) if e.may_have_been_processed() => WriteOutcome::Unknown,
+            Err(_) => WriteOutcome::Failed,
+        }
+    }
+}
+
+/// writes ops to --history-path as they complete
+pub struct History {
+    start: Instant,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl History {
+    pub fn create(path: &Path, start: Instant) -> Self {
+        let file = File::create(path)
+            .unwrap_or_else(|e| panic!("failed to create --history-path {}: {}", path.display(), e));
+        Self { start, out: Mutex::new(BufWriter::new(file)) }
+    }
+
+    fn micros(&self, t: Instant) -> u64 {
+        t.saturating_duration_since(self.start).as_micros() as u64
+    }
+
+    pub fn write(&self, user_id: Uuid, items: &[fitbod::Workout], invoked: Instant, outcome: WriteOutcome) {
+        self.record(&Op {
+            user_id,
+            invoke_us: self.micros(invoked),
+            complete_us: self.micros(Instant::now()),
+            kind: OpKind::Write {
+                items: items.iter().map(|x| (x.workout_id, x.start_time)).collect(),
+                outcome,
+            },
+        });
+    }
+
+    pub fn read(&self, user_id: Uuid, window: &ListWindow, invoked: Instant, returned: Option<Vec<Uuid>>) {
+        self.record(&Op {
+            user_id,
+            invoke_us: self.micros(invoked),
+            complete_us: self.micros(Instant::now()),
+            kind: OpKind::Read { start: window.start, end: window.end, limit: window.limit, returned },
+        });
+    }
+
+    fn record(&self, op: &Op) {
+        let mut out = self.out.lock().unwrap();
+        serde_json::to_writer(&mut *out, op).unwrap();
+        out.write_all(b"\n").unwrap();
+    }
+
+    pub fn flush(&self) {
+        self.out.lock().unwrap().flush().unwrap();
+    }
+}
+
+pub fn load(path: &Path) -> Vec<Op> {
+    let file = File::open(path)
+        .unwrap_or_else(|e| panic!("failed to open history {}: {}", path.display(), e));
+    io::BufReader::new(file).lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let line = line.unwrap();
+            serde_json::from_str(&line)
+                .unwrap_or_else(|e| panic!("failed to parse line {} of history {}: {}", i + 1, path.display(), e))
+        }).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    Phantom,
+    OutsideWindow,
+    StaleRead,
+    NonMonotonicRead,
+}
+
+impl ViolationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ViolationKind::Phantom => "phantom read",
+            ViolationKind::OutsideWindow => "outside window",
+            ViolationKind::StaleRead => "stale read",
+            ViolationKind::NonMonotonicRead => "non-monotonic read",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub user_id: Uuid,
+    pub read_invoke_us: u64,
+    pub read_complete_us: u64,
+    pub workout_id: Uuid,
+    pub detail: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: user id {}, read at {:.6}s..{:.6}s, workout_id {} {}",
+            self.kind.as_str(),
+            self.user_id,
+            self.read_invoke_us as f64 / 1e6,
+            self.read_complete_us as f64 / 1e6,
+            self.workout_id,
+            self.detail,
+        )
+    }
+}
+
+/// what the history says about one workout
+struct Written {
+    start_time: DateTime<Utc>,
+    /// earliest invocation of a write that may have applied it
+    first_invoke_us: u64,
+    /// earliest completion of a successful write of it
+    first_ack_us: Option<u64>,
+    /// earliest completion of a read that returned it
+    first_seen_us: Option<u64>,
+}
+
+/// checks every user's reads against the writes and other reads in `ops` (see module docs).
+/// returns the violations found, ordered by user and read.
+pub fn check(ops: &[Op]) -> Vec<Violation> {
+    let mut by_user: HashMap<Uuid, Vec<&Op>> = HashMap::new();
+    for op in ops {
+        by_user.entry(op.user_id).or_default().push(op);
+    }
+    let mut user_ids: Vec<Uuid> = by_user.keys().cloned().collect();
+    user_ids.sort();
+
+    let mut out = Vec::new();
+    for user_id in user_ids {
+        check_user(user_id, &by_user[&user_id], &mut out);
+    }
+    out
+}
+
+fn check_user(user_id: Uuid, ops: &[&Op], out: &mut Vec<Violation>) {
+    let mut written: HashMap<Uuid, Written> = HashMap::new();
+    for op in ops {
+        if let OpKind::Write { items, outcome } = &op.kind {
+            if *outcome == WriteOutcome::Failed { continue }
+            for (workout_id, start_time) in items {
+                let w = written.entry(*workout_id).or_insert(Written {
+                    start_time: *start_time,
+                    first_invoke_us: op.invoke_us,
+                    first_ack_us: None,
+                    first_seen_us: None,
+                });
+                w.first_invoke_us = w.first_invoke_us.min(op.invoke_us);
+                if *outcome == WriteOutcome::Ok {
+                    w.first_ack_us = Some(w.first_ack_us.map(|x| x.min(op.complete_us)).unwrap_or(op.complete_us));
+                }
+            }
+        }
+    }
+
+    let mut reads: Vec<(&Op, ListWindow, &[Uuid])> = ops.iter()
+        .filter_map(|op| match &op.kind {
+            OpKind::Read { start, end, limit, returned: Some(returned) } => {
+                Some((*op, ListWindow { start: *start, end: *end, limit: *limit }, &returned[..]))
+            }
+            _ => None,
+        }).collect();
+    reads.sort_by_key(|(op, ..)| (op.invoke_us, op.complete_us));
+
+    for (op, _, returned) in reads.iter() {
+        for workout_id in returned.iter() {
+            if let Some(w) = written.get_mut(workout_id) {
+                w.first_seen_us = Some(w.first_seen_us.map(|x| x.min(op.complete_us)).unwrap_or(op.complete_us));
+            }
+        }
+    }
+
+    for (op, window, returned) in reads.iter() {
+        let violation = |kind, workout_id, detail| Violation {
+            kind,
+            user_id,
+            read_invoke_us: op.invoke_us,
+            read_complete_us: op.complete_us,
+            workout_id,
+            detail,
+        };
+
+        for workout_id in returned.iter() {
+            match written.get(workout_id) {
+                None => out.push(violation(ViolationKind::Phantom, *workout_id, "was never written (or only by requests the server rejected)".to_string())),
+                Some(w) if w.first_invoke_us > op.complete_us => {
+                    out.push(violation(ViolationKind::Phantom, *workout_id,
+                        format!("was first written at {:.6}s, after the read completed", w.first_invoke_us as f64 / 1e6)));
+                }
+                Some(w) if ! window.contains(w.start_time) => {
+                    out.push(violation(ViolationKind::OutsideWindow, *workout_id,
+                        format!("has start_time {}, outside the requested window", w.start_time.to_rfc3339())));
+                }
+                Some(_) => {}
+            }
+        }
+
+        // if the response was cut off at the limit, only workouts newer than the last one
+        // returned are required
+        let cutoff = match window.limit == Some(returned.len()) {
+            true => Some(returned.iter().filter_map(|x| written.get(x)).map(|w| w.start_time).min()),
+            false => None,
+        };
+        let returned: HashSet<&Uuid> = returned.iter().collect();
+        for (workout_id, w) in written.iter() {
+            if returned.contains(workout_id) || ! window.contains(w.start_time) { continue }
+            match cutoff {
+                Some(Some(oldest)) if w.start_time <= oldest => continue,
+                Some(None) => continue,
+                _ => {}
+            }
+            if let Some(ack) = w.first_ack_us.filter(|x| *x < op.invoke_us) {
+                out.push(violation(ViolationKind::StaleRead, *workout_id,
+                    format!("is missing, but its write completed at {:.6}s", ack as f64 / 1e6)));
+            } else if let Some(seen) = w.first_seen_us.filter(|x| *x < op.invoke_us) {
+                out.push(violation(ViolationKind::NonMonotonicRead, *workout_id,
+                    format!("is missing, but an earlier read (completed at {:.6}s) returned it", seen as f64 / 1e6)));
+            }
+        }
+    }
+}
+
+/// prints the result of `check`. returns whether any violations were found.
+pub fn print_check(ops: &[Op], violations: &[Violation], max_shown: usize) -> bool {
+    let n_reads = ops.iter().filter(|x| matches!(x.kind, OpKind::Read { .. })).count();
+    println!("checked history of {} ops ({} reads, {} writes): {} violations",
+        ops.len(), n_reads, ops.len() - n_reads, violations.len());
+    for kind in [ViolationKind::Phantom, ViolationKind::OutsideWindow, ViolationKind::StaleRead, ViolationKind::NonMonotonicRead].iter() {
+        let n = violations.iter().filter(|x| x.kind == *kind).count();
+        if n > 0 {
+            println!("    {:<20} {:>10}", kind.as_str(), n);
+        }
+    }
+    for violation in violations.iter().take(max_shown) {
+        println!("  {}", violation);
+    }
+    if violations.len() > max_shown {
+        println!("  ... and {} more", violations.len() - max_shown);
+    }
+    ! violations.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn t(day: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_600_000_000 + day * 86_400, 0)
+    }
+
+    fn write(user_id: Uuid, invoke_us: u64, complete_us: u64, items: &[(Uuid, DateTime<Utc>)], outcome: WriteOutcome) -> Op {
+        Op { user_id, invoke_us, complete_us, kind: OpKind::Write { items: items.to_vec(), outcome } }
+    }
+
+    fn read(user_id: Uuid, invoke_us: u64, complete_us: u64, window: ListWindow, returned: &[Uuid]) -> Op {
+        let ListWindow { start, end, limit } = window;
+        Op { user_id, invoke_us, complete_us, kind: OpKind::Read { start, end, limit, returned: Some(returned.to_vec()) } }
+    }
+
+    fn kinds(ops: &[Op]) -> Vec<(ViolationKind, Uuid)> {
+        check(ops).into_iter().map(|x| (x.kind, x.workout_id)).collect()
+    }
+
+    #[test]
+    fn consistent_history_has_no_violations() {
+        let (user, a, b) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let ops = vec![
+            write(user, 0, 100, &[(a, t(1))], WriteOutcome::Ok),
+            read(user, 150, 200, ListWindow::default(), &[a]),
+            // overlaps the write of b, so may or may not return it
+            write(user, 300, 400, &[(b, t(2))], WriteOutcome::Ok),
+            read(user, 310, 320, ListWindow::default(), &[a]),
+            read(user, 330, 340, ListWindow::default(), &[b, a]),
+            read(user, 500, 600, ListWindow::default(), &[b, a]),
+        ];
+        assert_eq!(kinds(&ops), vec![]);
+    }
+
+    #[test]
+    fn phantom_read_of_workout_never_written() {
+        let (user, a) = (Uuid::new_v4(), Uuid::new_v4());
+        let ops = vec![read(user, 0, 100, ListWindow::default(), &[a])];
+        assert_eq!(kinds(&ops), vec![(ViolationKind::Phantom, a)]);
+    }
+
+    #[test]
+    fn phantom_read_of_workout_only_written_by_failed_request() {
+        let (user, a) = (Uuid::new_v4(), Uuid::new_v4());
+        let ops = vec![
+            write(user, 0, 100, &[(a, t(1))], WriteOutcome::Failed),
+            read(user, 150, 200, ListWindow::default(), &[a]),
+        ];
+        assert_eq!(kinds(&ops), vec![(ViolationKind::Phantom, a)]);
+    }
+
+    #[test]
+    fn phantom_read_of_workout_written_after_read_completed() {
+        let (user, a) = (Uuid::new_v4(), Uuid::new_v4());
+        let ops = vec![
+            read(user, 0, 100, ListWindow::default(), &[a]),
+            write(user, 200, 300, &[(a, t(1))], WriteOutcome::Ok),
+        ];
+        assert_eq!(kinds(&ops), vec![(ViolationKind::Phantom, a)]);
+    }
+
+    #[test]
+    fn read_outside_window() {
+        let (user, a, b) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let window = ListWindow { start: Some(t(2)), end: Some(t(3)), limit: None };
+        let ops = vec![
+            write(user, 0, 100, &[(a, t(1)), (b, t(3))], WriteOutcome::Ok),
+            // neither is required (both are outside the window), and both are flagged
+            read(user, 150, 200, window, &[b, a]),
+            read(user, 250, 300, window, &[]),
+        ];
+        assert_eq!(kinds(&ops), vec![(ViolationKind::OutsideWindow, b), (ViolationKind::OutsideWindow, a)]);
+    }
+
+    #[test]
+    fn stale_read_after_acked_write() {
+        let (user, a) = (Uuid::new_v4(), Uuid::new_v4());
+        let ops = vec![
+            write(user, 0, 100, &[(a, t(1))], WriteOutcome::Ok),
+            read(user, 150, 200, ListWindow::default(), &[]),
+            // a read invoked before the write completed isn't required to return it
+            read(user, 50, 120, ListWindow::default(), &[]),
+        ];
+        assert_eq!(kinds(&ops), vec![(ViolationKind::StaleRead, a)]);
+    }
+
+    #[test]
+    fn non_monotonic_read() {
+        let (user, a) = (Uuid::new_v4(), Uuid::new_v4());
+        let ops = vec![
+            write(user, 0, 100, &[(a, t(1))], WriteOutcome::Unknown),
+            read(user, 110, 120, ListWindow::default(), &[a]),
+            read(user, 130, 140, ListWindow::default(), &[]),
+        ];
+        assert_eq!(kinds(&ops), vec![(ViolationKind::NonMonotonicRead, a)]);
+    }
+
+    #[test]
+    fn unknown_write_is_not_required() {
+        let (user, a) = (Uuid::new_v4(), Uuid::new_v4());
+        let ops = vec![
+            write(user, 0, 100, &[(a, t(1))], WriteOutcome::Unknown),
+            read(user, 150, 200, ListWindow::default(), &[]),
+            read(user, 250, 300, ListWindow::default(), &[]),
+        ];
+        assert_eq!(kinds(&ops), vec![]);
+    }
+
+    #[test]
+    fn unknown_write_then_acked_resend_is_required() {
+        let (user, a) = (Uuid::new_v4(), Uuid::new_v4());
+        let ops = vec![
+            write(user, 0, 100, &[(a, t(1))], WriteOutcome::Unknown),
+            write(user, 200, 300, &[(a, t(1))], WriteOutcome::Ok),
+            read(user, 400, 500, ListWindow::default(), &[]),
+        ];
+        assert_eq!(kinds(&ops), vec![(ViolationKind::StaleRead, a)]);
+    }
+
+    #[test]
+    fn limit_only_requires_workouts_newer_than_last_returned() {
+        let user = Uuid::new_v4();
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let limit = ListWindow { limit: Some(2), ..Default::default() };
+        let ops = vec![
+            write(user, 0, 100, &[(a, t(1)), (b, t(2)), (c, t(3))], WriteOutcome::Ok),
+            // cut off at the limit: a is older than the last one returned
+            read(user, 150, 200, limit, &[c, b]),
+            // not cut off, so a (and c) are missing
+            read(user, 250, 300, limit, &[b]),
+            // cut off at the limit, but skips b, which is newer than a
+            read(user, 350, 400, limit, &[c, a]),
+        ];
+        let mut got: Vec<Uuid> = check(&ops).iter().map(|x| x.workout_id).collect();
+        got.sort();
+        let mut want = vec![a, c, b];
+        want.sort();
+        assert_eq!(got, want);
+        assert!(check(&ops).iter().all(|x| x.kind == ViolationKind::StaleRead));
+    }
+
+    #[test]
+    fn users_are_checked_independently() {
+        let (u1, u2, a) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let ops = vec![
+            write(u1, 0, 100, &[(a, t(1))], WriteOutcome::Ok),
+            read(u2, 150, 200, ListWindow::default(), &[]),
+            read(u2, 250, 300, ListWindow::default(), &[a]),
+        ];
+        assert_eq!(kinds(&ops), vec![(ViolationKind::Phantom, a)]);
+    }
+}
@@ -0,0 +1,988 @@
+//! minimal http/1.1 client used to send signed requests to the api server
+
+use std::time::*;
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, TcpStream};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::fmt;
+use serde::{Serialize, de::DeserializeOwned};
+use chrono::prelude::*;
+use structopt::StructOpt;
+
+use crate::API_REQUEST;
+use crate::stats::LatencyShard;
+use crate::metrics::MetricsSink;
+use crate::dashboard;
+
+/// how many bytes to read from the socket at a time
+const READ_SIZE: usize = 16384;
+
+/// responses larger than this are treated as malformed rather than buffered indefinitely
+const MAX_RESPONSE_LEN: usize = 64 * 1024 * 1024;
+
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+const MAX_CHUNK_LINE_LEN: usize = 1024;
+
+/// how much of a malformed response to print
+const MAX_DISPLAY_LEN: usize = 4096;
+
+/// how connections to the api server are managed by each worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionMode {
+    /// open a new connection for every request, and close it after reading the response
+    PerRequest,
+    /// reuse http/1.1 keep-alive connections across requests
+    KeepAlive,
+}
+
+impl ConnectionMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionMode::PerRequest => "per-request",
+            ConnectionMode::KeepAlive => "keep-alive",
+        }
+    }
+
+    /// value of the `connection` header sent with each request
+    fn header_value(&self) -> &'static str {
+        match self {
+            ConnectionMode::PerRequest => "close",
+            ConnectionMode::KeepAlive => "keep-alive",
+        }
+    }
+}
+
+impl FromStr for ConnectionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per-request" => Ok(ConnectionMode::PerRequest),
+            "keep-alive" => Ok(ConnectionMode::KeepAlive),
+            other => Err(format!("invalid connection mode '{}' (expected per-request or keep-alive)", other)),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// command line options shared by every subcommand that sends requests to the api server
+#[derive(StructOpt, Debug, Clone)]
+pub struct HttpOpts {
+    /// api server address
+    #[structopt(short, long, default_value = "127.0.0.1:3030")]
+    pub connect: SocketAddr,
+
+    /// per-request: open a new tcp connection for every request.
+    /// keep-alive: each worker reuses a pool of http/1.1 keep-alive connections
+    #[structopt(long, default_value = "keep-alive")]
+    pub connection_mode: ConnectionMode,
+
+    /// number of keep-alive connections each worker rotates between (ignored in
+    /// per-request mode)
+    #[structopt(long, default_value = "1")]
+    pub pool_size: usize,
+
+    /// how long to wait for a tcp connection to the api server to be established
+    #[structopt(long, default_value = "2s", parse(try_from_str = humantime::parse_duration))]
+    pub connect_timeout: Duration,
+
+    /// how long to wait for the request to be fully written to the socket
+    #[structopt(long, default_value = "5s", parse(try_from_str = humantime::parse_duration))]
+    pub write_timeout: Duration,
+
+    /// how long to wait for the full response after the request has been written
+    #[structopt(long, default_value = "10s", parse(try_from_str = humantime::parse_duration))]
+    pub read_timeout: Duration,
+}
+
+/// stage of a request at which a deadline passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Connect,
+    Write,
+    Read,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Connect => "connect",
+            Phase::Write => "write",
+            Phase::Read => "read",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// the connect, write or read deadline passed before the request completed. the
+    /// server may or may not have processed the request.
+    Timeout(Phase),
+    /// an i/o error other than a timeout: connection refused, reset, closed by the server
+    /// before the response arrived, etc.
+    Transport(Phase, io::ErrorKind),
+    /// the server responded with a status other than 200 or 204. includes the response body.
+    Status(u16, Vec<u8>),
+    /// the server sent a response that could not be parsed: invalid status line or headers,
+    /// bad chunked encoding, body shorter than its content-length, etc.
+    Malformed(String),
+    /// the response body is not the json expected (see `decode`)
+    Decode(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Status(status, body) => write!(f, "http {}: {}", status,
+                String::from_utf8_lossy(&body[..body.len().min(MAX_DISPLAY_LEN)])),
+            ApiError::Malformed(msg) => write!(f, "malformed response: {}", msg),
+            ApiError::Decode(msg) => f.write_str(msg),
+            _ => f.write_str(&self.class()),
+        }
+    }
+}
+
+impl ApiError {
+    /// failure class used to count errors, e.g. "timeout (read)", "http 503" or
+    /// "connect error: connection refused"
+    pub fn class(&self) -> String {
+        match self {
+            ApiError::Timeout(phase) => format!("timeout ({})", phase.as_str()),
+            ApiError::Transport(phase, kind) => format!("{} error: {}", phase.as_str(), io::Error::from(*kind)),
+            ApiError::Status(status, _) => format!("http {}", status),
+            ApiError::Malformed(_) => "malformed response".to_string(),
+            ApiError::Decode(_) => "invalid json".to_string(),
+        }
+    }
+
+    /// whether the server may have acted on the request despite the error. only failures to
+    /// connect and 4xx responses rule it out.
+    pub fn may_have_been_processed(&self) -> bool {
+        match self {
+            ApiError::Timeout(phase) | ApiError::Transport(phase, _) => *phase != Phase::Connect,
+            ApiError::Status(status, _) => ! (400..500).contains(status),
+            ApiError::Malformed(_) | ApiError::Decode(_) => true,
+        }
+    }
+}
+
+/// set of connections to the api server owned by a single worker
+///
+/// in keep-alive mode, new connections are opened until `size` have been created, after
+/// which requests rotate between the existing connections. connections the server has
+/// closed are discarded and replaced on demand.
+pub struct ConnectionPool {
+    addr: SocketAddr,
+    mode: ConnectionMode,
+    size: usize,
+    connect_timeout: Duration,
+    idle: VecDeque<TcpStream>,
+    n_open: usize,
+}
+
+impl ConnectionPool {
+    pub fn new(addr: SocketAddr, mode: ConnectionMode, size: usize, connect_timeout: Duration) -> Self {
+        assert!(size > 0, "pool size must be at least 1");
+        Self { addr, mode, size, connect_timeout, idle: VecDeque::with_capacity(size), n_open: 0 }
+    }
+
+    /// returns a connection and whether it was previously used for another request
+    fn checkout(&mut self) -> Result<(TcpStream, bool), ApiError> {
+        if self.mode == ConnectionMode::KeepAlive && self.n_open >= self.size {
+            if let Some(stream) = self.idle.pop_front() {
+                return Ok((stream, true))
+            }
+        }
+        let stream = self.connect()?;
+        Ok((stream, false))
+    }
+
+    fn connect(&mut self) -> Result<TcpStream, ApiError> {
+        let stream = TcpStream::connect_timeout(&self.addr, self.connect_timeout).map_err(|e| {
+            match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ApiError::Timeout(Phase::Connect),
+                kind => ApiError::Transport(Phase::Connect, kind),
+            }
+        })?;
+        stream.set_nodelay(true).expect("send nodelay");
+        if self.mode == ConnectionMode::KeepAlive {
+            self.n_open += 1;
+        }
+        Ok(stream)
+    }
+
+    /// return a connection after a successful request. `reusable` is false if the server
+    /// indicated it is going to close the connection.
+    fn checkin(&mut self, stream: TcpStream, reusable: bool) {
+        match self.mode {
+            ConnectionMode::KeepAlive if reusable => self.idle.push_back(stream),
+            ConnectionMode::KeepAlive => self.discard(stream),
+            ConnectionMode::PerRequest => {}
+        }
+    }
+
+    /// drop a connection that failed or was closed by the server
+    fn discard(&mut self, stream: TcpStream) {
+        drop(stream);
+        if self.mode == ConnectionMode::KeepAlive {
+            self.n_open = self.n_open.saturating_sub(1);
+        }
+    }
+}
+
+/// result of sending a request over a single connection
+enum Attempt {
+    /// response headers were parsed and the full body received
+    Done { status: u16, body: Vec<u8>, keep_alive: bool, raw: Vec<u8> },
+    /// the connection was closed (or reset) before any part of the response arrived.
+    /// on a reused keep-alive connection, this means the server closed it while idle.
+    Closed(Phase, io::ErrorKind),
+    TimedOut(Phase),
+    /// error message and the bytes received so far
+    Malformed(String, Vec<u8>),
+    /// any other i/o error
+    Failed(Phase, io::ErrorKind),
+}
+
+/// sends signed requests to the api server, reusing connections according to
+/// the configured `ConnectionMode`
+pub struct ApiClient {
+    pool: ConnectionPool,
+    tera: tera::Tera,
+    metrics: MetricsSink,
+    latencies: LatencyShard,
+    write_timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl ApiClient {
+    /// latency of every request is recorded in `latencies` in addition to being sent to `metrics`
+    pub fn new(opts: &HttpOpts, metrics: MetricsSink, latencies: LatencyShard) -> Self {
+        let pool = ConnectionPool::new(opts.connect, opts.connection_mode, opts.pool_size, opts.connect_timeout);
+        Self {
+            pool,
+            tera: request_template(),
+            metrics,
+            latencies,
+            write_timeout: opts.write_timeout,
+            read_timeout: opts.read_timeout,
+        }
+    }
+
+    pub fn request<T>(&mut self, path: &str, req: &T, key: &fitbod::auth::PrivateKey) -> Result<Vec<u8>, ApiError>
+        where T: Serialize
+    {
+        self.request_scheduled(path, req, key, None)
+    }
+
+    /// like `request`, but if `scheduled` is provided, latency is measured from then (the
+    /// time the request was supposed to be sent) instead of from when it was actually sent
+    pub fn request_scheduled<T>(
+        &mut self,
+        path: &str,
+        req: &T,
+        key: &fitbod::auth::PrivateKey,
+        scheduled: Option<Instant>,
+    ) -> Result<Vec<u8>, ApiError>
+        where T: Serialize
+    {
+        let http_req_str = render_request(&self.tera, path, req, key, self.pool.mode);
+        let req_start = scheduled.unwrap_or_else(Instant::now);
+        let attempt = self.exchange(path, http_req_str.as_bytes(), req_start)?;
+        complete(&self.metrics, &self.latencies, self.pool.mode, path, &http_req_str, req_start, attempt)
+    }
+
+    /// sends `http_req` (the full text of an http request, e.g. one built by `auth-test` with
+    /// altered headers) as is. returns the response status and body; unlike `request`, a
+    /// status other than 200 or 204 isn't treated as a failure.
+    pub fn request_raw(&mut self, path: &str, http_req: &str) -> Result<(u16, Vec<u8>), ApiError> {
+        let req_start = Instant::now();
+        let attempt = self.exchange(path, http_req.as_bytes(), req_start)?;
+        received(&self.metrics, &self.latencies, self.pool.mode, path, http_req, req_start, attempt)
+            .map(|(status, body, _raw)| (status, body))
+    }
+
+    /// sends the request over a pooled connection, reconnecting once if the server closed an
+    /// idle keep-alive connection. fails only if a connection can't be established.
+    fn exchange(&mut self, path: &str, http_req: &[u8], req_start: Instant) -> Result<Attempt, ApiError> {
+        let (mut stream, reused) = match self.pool.checkout() {
+            Ok(x) => x,
+            Err(e) => return Err(record_failure(&self.metrics, &self.latencies, self.pool.mode, path, req_start, e)),
+        };
+        let mut attempt = send(&mut stream, http_req, self.write_timeout, self.read_timeout);
+        if let (Attempt::Closed(..), true) = (&attempt, reused) {
+            // server closed the idle keep-alive connection - reconnect and try once more
+            self.pool.discard(stream);
+            stream = match self.pool.connect() {
+                Ok(stream) => stream,
+                Err(e) => return Err(record_failure(&self.metrics, &self.latencies, self.pool.mode, path, req_start, e)),
+            };
+            attempt = send(&mut stream, http_req, self.write_timeout, self.read_timeout);
+        }
+
+        match attempt {
+            Attempt::Done { keep_alive, .. } => self.pool.checkin(stream, keep_alive),
+            _ => self.pool.discard(stream),
+        }
+        Ok(attempt)
+    }
+}
+
+/// async counterpart to `ApiClient`, used by the virtual users of the async engine. each
+/// client holds at most one connection, which is reused in keep-alive mode.
+pub struct AsyncApiClient {
+    addr: SocketAddr,
+    mode: ConnectionMode,
+    conn: Option<tokio::net::TcpStream>,
+    tera: tera::Tera,
+    metrics: MetricsSink,
+    latencies: LatencyShard,
+    connect_timeout: Duration,
+    write_timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl AsyncApiClient {
+    pub fn new(opts: &HttpOpts, metrics: MetricsSink, latencies: LatencyShard) -> Self {
+        Self {
+            addr: opts.connect,
+            mode: opts.connection_mode,
+            conn: None,
+            tera: request_template(),
+            metrics,
+            latencies,
+            connect_timeout: opts.connect_timeout,
+            write_timeout: opts.write_timeout,
+            read_timeout: opts.read_timeout,
+        }
+    }
+
+    /// see `ApiClient::request_scheduled` re: `scheduled`
+    pub async fn request<T>(
+        &mut self,
+        path: &str,
+        req: &T,
+        key: &fitbod::auth::PrivateKey,
+        scheduled: Option<Instant>,
+    ) -> Result<Vec<u8>, ApiError>
+        where T: Serialize
+    {
+        let http_req_str = render_request(&self.tera, path, req, key, self.mode);
+        let http_req = http_req_str.as_bytes();
+
+        let req_start = scheduled.unwrap_or_else(Instant::now);
+
+        let (mut stream, reused) = match self.conn.take() {
+            Some(stream) => (stream, true),
+            None => match connect_async(self.addr, self.connect_timeout).await {
+                Ok(stream) => (stream, false),
+                Err(e) => return Err(record_failure(&self.metrics, &self.latencies, self.mode, path, req_start, e)),
+            }
+        };
+        let mut attempt = send_async(&mut stream, http_req, self.write_timeout, self.read_timeout).await;
+        if let (Attempt::Closed(..), true) = (&attempt, reused) {
+            // server closed the idle keep-alive connection - reconnect and try once more
+            stream = match connect_async(self.addr, self.connect_timeout).await {
+                Ok(stream) => stream,
+                Err(e) => return Err(record_failure(&self.metrics, &self.latencies, self.mode, path, req_start, e)),
+            };
+            attempt = send_async(&mut stream, http_req, self.write_timeout, self.read_timeout).await;
+        }
+
+        if let Attempt::Done { keep_alive: true, .. } = attempt {
+            if self.mode == ConnectionMode::KeepAlive {
+                self.conn = Some(stream);
+            }
+        }
+
+        complete(&self.metrics, &self.latencies, self.mode, path, &http_req_str, req_start, attempt)
+    }
+}
+
+// note: a free fn rather than a method so that `&self` is not held across the await, which
+// would require the client to be `Sync` to be used in a spawned task
+async fn connect_async(addr: SocketAddr, connect_timeout: Duration) -> Result<tokio::net::TcpStream, ApiError> {
+    match tokio::time::timeout(connect_timeout, tokio::net::TcpStream::connect(&addr)).await {
+        Ok(Ok(stream)) => {
+            stream.set_nodelay(true).expect("send nodelay");
+            Ok(stream)
+        }
+        Ok(Err(e)) if e.kind() == io::ErrorKind::TimedOut => Err(ApiError::Timeout(Phase::Connect)),
+        Ok(Err(e)) => Err(ApiError::Transport(Phase::Connect, e.kind())),
+        Err(_) => Err(ApiError::Timeout(Phase::Connect)),
+    }
+}
+
+fn request_template() -> tera::Tera {
+    let mut tera = tera::Tera::default();
+    tera.add_raw_template("api-request", API_REQUEST).unwrap();
+    tera
+}
+
+/// serializes and signs `req`, returning the full text of the http request
+fn render_request<T>(tera: &tera::Tera, path: &str, req: &T, key: &fitbod::auth::PrivateKey, mode: ConnectionMode) -> String
+    where T: Serialize
+{
+    let req_json = serde_json::to_string(&req).unwrap();
+    let timestamp = Utc::now().timestamp();
+    let sig = fitbod::auth::sign_request(timestamp, &req_json, key);
+    let timestamp_str = timestamp.to_string();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("path", path);
+    ctx.insert("body", &req_json);
+    ctx.insert("sig", &sig);
+    ctx.insert("timestamp", &timestamp_str);
+    ctx.insert("connection", mode.header_value());
+    tera.render("api-request", &ctx).unwrap()
+}
+
+/// records the outcome of a request, and converts it to the result returned to the caller.
+/// a status other than 200 or 204 is a failure (`ApiError::Status`).
+fn complete(
+    metrics: &MetricsSink,
+    latencies: &LatencyShard,
+    mode: ConnectionMode,
+    path: &str,
+    http_req_str: &str,
+    req_start: Instant,
+    attempt: Attempt,
+) -> Result<Vec<u8>, ApiError> {
+    let (status_code, body, raw) = received(metrics, latencies, mode, path, http_req_str, req_start, attempt)?;
+    if ! (status_code == 200 || status_code == 204) {
+        dashboard::failure(format!("{} -> {} response", path, status_code), Some(format!("***\nREQUEST:\n\n{}\n\nRESPONSE:\n\n{}\n",
+            http_req_str,
+            String::from_utf8_lossy(&raw[..]),
+        )));
+        return Err(ApiError::Status(status_code, body))
+    }
+    Ok(body)
+}
+
+/// records the outcome of a request. returns the response's status, body and raw bytes, or
+/// an error if no usable response was received.
+fn received(
+    metrics: &MetricsSink,
+    latencies: &LatencyShard,
+    mode: ConnectionMode,
+    path: &str,
+    http_req_str: &str,
+    req_start: Instant,
+    attempt: Attempt,
+) -> Result<(u16, Vec<u8>, Vec<u8>), ApiError> {
+    match attempt {
+        Attempt::Done { status: status_code, body, raw, .. } => {
+            let req_done = Instant::now();
+            let elapsed = req_done.saturating_duration_since(req_start);
+            let status = status_code.to_string();
+
+            latencies.lock().unwrap().record(path, &status, elapsed);
+            metrics.api_req(path, &status, mode.as_str(), None, elapsed);
+            Ok((status_code, body, raw))
+        }
+
+        Attempt::TimedOut(phase) => Err(record_failure(metrics, latencies, mode, path, req_start, ApiError::Timeout(phase))),
+
+        Attempt::Malformed(msg, raw) => {
+            dashboard::failure(format!("{} -> malformed response: {}", path, msg), Some(format!("***\nMALFORMED RESPONSE ({}):\n\nREQUEST:\n\n{}\n\nRESPONSE ({} bytes):\n\n{}\n",
+                msg,
+                http_req_str,
+                raw.len(),
+                String::from_utf8_lossy(&raw[..raw.len().min(MAX_DISPLAY_LEN)]),
+            )));
+            Err(record_failure(metrics, latencies, mode, path, req_start, ApiError::Malformed(msg)))
+        }
+
+        Attempt::Closed(phase, kind) | Attempt::Failed(phase, kind) => {
+            Err(record_failure(metrics, latencies, mode, path, req_start, ApiError::Transport(phase, kind)))
+        }
+    }
+}
+
+/// records a request that did not produce a usable response, passing `err` through
+fn record_failure(
+    metrics: &MetricsSink,
+    latencies: &LatencyShard,
+    mode: ConnectionMode,
+    path: &str,
+    req_start: Instant,
+    err: ApiError,
+) -> ApiError {
+    let elapsed = Instant::now().saturating_duration_since(req_start);
+    match err {
+        ApiError::Timeout(phase) => {
+            dashboard::failure(format!("{} -> timeout ({}) after {:?}", path, phase.as_str(), elapsed), None);
+            latencies.lock().unwrap().record(path, "timeout", elapsed);
+            metrics.api_req(path, "timeout", mode.as_str(), Some(phase.as_str()), elapsed);
+        }
+
+        ApiError::Malformed(_) => {
+            latencies.lock().unwrap().record(path, "malformed", elapsed);
+            metrics.api_req(path, "malformed", mode.as_str(), None, elapsed);
+        }
+
+        ApiError::Transport(phase, kind) => {
+            dashboard::failure(format!("{} -> {} error after {:?}: {}", path, phase.as_str(), elapsed, io::Error::from(kind)), None);
+            latencies.lock().unwrap().record(path, "transport", elapsed);
+            metrics.api_req(path, "transport", mode.as_str(), Some(phase.as_str()), elapsed);
+        }
+
+        // non-2xx responses are recorded by `complete` under their status code, and invalid
+        // json is only detected after the request has been recorded
+        ApiError::Status(..) | ApiError::Decode(_) => {}
+    }
+    err
+}
+
+/// deserializes a json response body, treating invalid json as a server defect
+pub fn decode<T>(body: &[u8]) -> Result<T, ApiError>
+    where T: DeserializeOwned
+{
+    serde_json::from_slice(body).map_err(|e| {
+        ApiError::Decode(format!("invalid json in response body ({} bytes): {}", body.len(), e))
+    })
+}
+
+/// writes the request and reads the response using blocking socket calls. the socket's
+/// read/write timeouts are set to the time remaining before the deadline prior to each
+/// call, so the thread sleeps in the kernel until the socket is ready rather than spinning.
+fn send(stream: &mut TcpStream, http_req: &[u8], write_timeout: Duration, read_timeout: Duration) -> Attempt {
+    let write_deadline = Instant::now() + write_timeout;
+    let mut n_bytes_written = 0;
+    while n_bytes_written < http_req.len() {
+        let remaining = match until(write_deadline) {
+            Some(d) => d,
+            None => return Attempt::TimedOut(Phase::Write),
+        };
+        stream.set_write_timeout(Some(remaining)).expect("set write timeout");
+        match stream.write(&http_req[n_bytes_written..]) {
+            Ok(n) => n_bytes_written += n,
+            Err(e) if timed_out(&e) => {} // loop around to check the deadline
+            Err(e) if closed(&e) => return Attempt::Closed(Phase::Write, e.kind()),
+            Err(e) => return Attempt::Failed(Phase::Write, e.kind()),
+        }
+    }
+
+    let read_deadline = Instant::now() + read_timeout;
+    let mut buf: Vec<u8> = Vec::with_capacity(READ_SIZE);
+    let mut chunk = [0u8; READ_SIZE];
+
+    loop {
+        let remaining = match until(read_deadline) {
+            Some(d) => d,
+            None => return Attempt::TimedOut(Phase::Read),
+        };
+        stream.set_read_timeout(Some(remaining)).expect("set read timeout");
+        let eof = match stream.read(&mut chunk[..]) {
+            Ok(0) if buf.is_empty() => return Attempt::Closed(Phase::Read, io::ErrorKind::UnexpectedEof),
+            Ok(0) => true,
+            Ok(n) => {
+                buf.extend_from_slice(&chunk[..n]);
+                false
+            }
+            Err(e) if timed_out(&e) => continue,
+            Err(e) if closed(&e) && buf.is_empty() => return Attempt::Closed(Phase::Read, e.kind()),
+            Err(e) => return Attempt::Failed(Phase::Read, e.kind()),
+        };
+
+        if let Some(attempt) = after_read(&mut buf, eof) { return attempt }
+    }
+}
+
+/// async version of `send`
+async fn send_async(stream: &mut tokio::net::TcpStream, http_req: &[u8], write_timeout: Duration, read_timeout: Duration) -> Attempt {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    match tokio::time::timeout(write_timeout, stream.write_all(http_req)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) if closed(&e) => return Attempt::Closed(Phase::Write, e.kind()),
+        Ok(Err(e)) => return Attempt::Failed(Phase::Write, e.kind()),
+        Err(_) => return Attempt::TimedOut(Phase::Write),
+    }
+
+    let read_deadline = tokio::time::Instant::now() + read_timeout;
+    let mut buf: Vec<u8> = Vec::with_capacity(READ_SIZE);
+    let mut chunk = vec![0u8; READ_SIZE];
+
+    loop {
+        let eof = match tokio::time::timeout_at(read_deadline, stream.read(&mut chunk[..])).await {
+            Ok(Ok(0)) if buf.is_empty() => return Attempt::Closed(Phase::Read, io::ErrorKind::UnexpectedEof),
+            Ok(Ok(0)) => true,
+            Ok(Ok(n)) => {
+                buf.extend_from_slice(&chunk[..n]);
+                false
+            }
+            Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Ok(Err(e)) if closed(&e) && buf.is_empty() => return Attempt::Closed(Phase::Read, e.kind()),
+            Ok(Err(e)) => return Attempt::Failed(Phase::Read, e.kind()),
+            Err(_) => return Attempt::TimedOut(Phase::Read),
+        };
+
+        if let Some(attempt) = after_read(&mut buf, eof) { return attempt }
+    }
+}
+
+/// checks whether the bytes read so far hold a complete response (or a defect), returning
+/// the outcome of the attempt if so, or `None` if more bytes are needed
+fn after_read(buf: &mut Vec<u8>, eof: bool) -> Option<Attempt> {
+    match parse_response(&buf[..], eof) {
+        Ok(Some(resp)) => {
+            // anything past the end of the response means we are out of sync with the
+            // server, so the connection can't be reused
+            let keep_alive = resp.keep_alive && resp.len == buf.len();
+            Some(Attempt::Done { status: resp.status, body: resp.body, keep_alive, raw: std::mem::take(buf) })
+        }
+
+        Ok(None) if eof => {
+            let msg = format!("connection closed after {} bytes, before end of response", buf.len());
+            Some(Attempt::Malformed(msg, std::mem::take(buf)))
+        }
+
+        Ok(None) if buf.len() > MAX_RESPONSE_LEN => {
+            let msg = format!("response exceeds {} bytes", MAX_RESPONSE_LEN);
+            Some(Attempt::Malformed(msg, std::mem::take(buf)))
+        }
+
+        Ok(None) => None,
+
+        Err(msg) => Some(Attempt::Malformed(msg, std::mem::take(buf))),
+    }
+}
+
+/// a complete response parsed from the bytes read off a connection
+struct ParsedResponse {
+    status: u16,
+    /// decoded body (i.e. with any chunked transfer encoding removed)
+    body: Vec<u8>,
+    /// false if the server asked to close the connection, or if the end of the body
+    /// is only marked by the connection closing
+    keep_alive: bool,
+    /// number of bytes the response occupies, including head and body
+    len: usize,
+}
+
+/// parses an http/1.1 response from `buf`, returning `Ok(None)` if more bytes are needed.
+///
+/// the body is framed according to (in order of precedence) `transfer-encoding: chunked`,
+/// `content-length`, or the connection closing (`eof`). framing that contradicts itself or
+/// can never complete is returned as an error describing the defect.
+fn parse_response(buf: &[u8], eof: bool) -> Result<Option<ParsedResponse>, String> {
+    let mut headers = Vec::<thhp::HeaderField>::with_capacity(16);
+    let (status, body_offset) = match thhp::Response::parse(buf, &mut headers) {
+        Ok(thhp::Complete((resp, body_offset))) => (resp.status, body_offset),
+        Ok(thhp::Incomplete) if buf.len() > MAX_HEAD_LEN => {
+            return Err(format!("response head exceeds {} bytes", MAX_HEAD_LEN))
+        }
+        Ok(thhp::Incomplete) => return Ok(None),
+        Err(e) => return Err(format!("invalid response head: {:?}", e)),
+    };
+    assert!(body_offset <= buf.len());
+
+    let mut content_length: Option<usize> = None;
+    let mut transfer_encoding: Option<&str> = None;
+    let mut keep_alive = true;
+    for h in headers.iter() {
+        if h.name.eq_ignore_ascii_case("content-length") {
+            let len = h.value.trim().parse::<usize>()
+                .map_err(|_| format!("invalid content-length header: {:?}", h.value))?;
+            match content_length {
+                Some(prev) if prev != len => {
+                    return Err(format!("conflicting content-length headers: {} vs. {}", prev, len))
+                }
+                _ => content_length = Some(len),
+            }
+        } else if h.name.eq_ignore_ascii_case("transfer-encoding") {
+            transfer_encoding = Some(h.value.trim());
+        } else if h.name.eq_ignore_ascii_case("connection") && h.value.trim().eq_ignore_ascii_case("close") {
+            keep_alive = false;
+        }
+    }
+
+    // responses that never include a body, regardless of headers
+    if (100..200).contains(&status) || status == 204 || status == 304 {
+        return Ok(Some(ParsedResponse { status, body: Vec::new(), keep_alive, len: body_offset }))
+    }
+
+    if let Some(te) = transfer_encoding {
+        if content_length.is_some() {
+            return Err(format!("response has both transfer-encoding ({:?}) and content-length", te))
+        }
+        let chunked = te.rsplit(',').next().map(|x| x.trim().eq_ignore_ascii_case("chunked")).unwrap_or(false);
+        if chunked {
+            return Ok(decode_chunked(&buf[body_offset..])?.map(|(body, n)| {
+                ParsedResponse { status, body, keep_alive, len: body_offset + n }
+            }))
+        }
+    } else if let Some(len) = content_length {
+        let body_end = body_offset + len;
+        if buf.len() < body_end { return Ok(None) }
+        let body = buf[body_offset..body_end].to_vec();
+        return Ok(Some(ParsedResponse { status, body, keep_alive, len: body_end }))
+    }
+
+    // body is delimited by the server closing the connection
+    if ! eof { return Ok(None) }
+    let body = buf[body_offset..].to_vec();
+    Ok(Some(ParsedResponse { status, body, keep_alive: false, len: buf.len() }))
+}
+
+/// decodes a chunked body at the start of `buf`, returning the body and number of
+/// bytes consumed, or `Ok(None)` if the final chunk has not arrived yet
+fn decode_chunked(buf: &[u8]) -> Result<Option<(Vec<u8>, usize)>, String> {
+    let mut body = Vec::with_capacity(buf.len());
+    let mut pos = 0;
+    loop {
+        let line_end = match find_crlf(&buf[pos..]) {
+            Some(i) => pos + i,
+            None if buf.len() - pos > MAX_CHUNK_LINE_LEN => {
+                return Err(format!("chunk size line exceeds {} bytes", MAX_CHUNK_LINE_LEN))
+            }
+            None => return Ok(None),
+        };
+        let line = std::str::from_utf8(&buf[pos..line_end])
+            .map_err(|_| "chunk size line is not valid utf-8".to_string())?;
+        let size_str = line.split(';').next().unwrap_or("").trim(); // ignore chunk extensions
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|_| format!("invalid chunk size line: {:?}", line))?;
+        pos = line_end + 2;
+
+        if size == 0 {
+            // optional trailer fields, terminated by an empty line
+            loop {
+                let line_end = match find_crlf(&buf[pos..]) {
+                    Some(i) => pos + i,
+                    None => return Ok(None),
+                };
+                let is_empty = line_end == pos;
+                pos = line_end + 2;
+                if is_empty { return Ok(Some((body, pos))) }
+            }
+        }
+
+        let data_end = pos.checked_add(size)
+            .filter(|&x| x <= MAX_RESPONSE_LEN)
+            .ok_or_else(|| format!("chunk size {} exceeds max response length", size))?;
+        if buf.len() < data_end + 2 { return Ok(None) }
+        body.extend_from_slice(&buf[pos..data_end]);
+        if &buf[data_end..(data_end + 2)] != b"\r\n" {
+            return Err(format!("chunk of {} bytes not followed by crlf", size))
+        }
+        pos = data_end + 2;
+    }
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|x| x == b"\r\n")
+}
+
+/// time left before `deadline`, or `None` if it has passed
+fn until(deadline: Instant) -> Option<Duration> {
+    deadline.checked_duration_since(Instant::now()).filter(|d| *d > Duration::from_micros(0))
+}
+
+/// a blocking socket call returned because its timeout elapsed (`WouldBlock` on unix,
+/// `TimedOut` on windows), or was interrupted by a signal
+fn timed_out(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted)
+}
+
+fn closed(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// feeds `raw` to `after_read` `step` bytes at a time, as if read off a connection that
+    /// the server closes after sending it
+    fn feed(raw: &[u8], step: usize) -> Attempt {
+        let mut buf = Vec::new();
+        for piece in raw.chunks(step) {
+            buf.extend_from_slice(piece);
+            if let Some(attempt) = after_read(&mut buf, false) { return attempt }
+        }
+        after_read(&mut buf, true).expect("eof always completes the attempt")
+    }
+
+    /// `feed`s `raw` with every read size, and checks each gives a response with `status`,
+    /// `body` and `keep_alive`
+    fn assert_done(raw: &[u8], status: u16, body: &[u8], keep_alive: bool) {
+        for step in 1..=raw.len() {
+            match feed(raw, step) {
+                Attempt::Done { status: s, body: b, keep_alive: k, .. } => {
+                    assert_eq!((s, &b[..], k), (status, body, keep_alive), "read size {}", step);
+                }
+                Attempt::Malformed(msg, _) => panic!("read size {}: malformed: {}", step, msg),
+                _ => panic!("read size {}: unexpected outcome", step),
+            }
+        }
+    }
+
+    fn assert_malformed(raw: &[u8], contains: &str) {
+        match feed(raw, raw.len().max(1)) {
+            Attempt::Malformed(msg, _) => assert!(msg.contains(contains), "{:?} doesn't contain {:?}", msg, contains),
+            _ => panic!("expected malformed response for {:?}", String::from_utf8_lossy(raw)),
+        }
+    }
+
+    #[test]
+    fn content_length_body() {
+        assert_done(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello", 200, b"hello", true);
+        assert_done(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", 200, b"", true);
+        assert_done(b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 5\r\ncontent-length: 5\r\n\r\noops!", 500, b"oops!", true);
+    }
+
+    #[test]
+    fn bytes_past_end_of_response_prevent_reuse() {
+        let raw = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nokHTTP/1.1";
+        assert!(matches!(feed(raw, raw.len()), Attempt::Done { keep_alive: false, ref body, .. } if body == b"ok"));
+    }
+
+    #[test]
+    fn no_body_statuses() {
+        assert_done(b"HTTP/1.1 204 No Content\r\n\r\n", 204, b"", true);
+        // a 204 has no body even if it claims a length
+        assert_done(b"HTTP/1.1 204 No Content\r\ncontent-length: 10\r\n\r\n", 204, b"", true);
+    }
+
+    #[test]
+    fn chunked_body() {
+        assert_done(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n", 200, b"hello, world", true);
+        assert_done(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\nA\r\n0123456789\r\n0\r\n\r\n", 200, b"0123456789", true);
+        assert_done(b"HTTP/1.1 200 OK\r\ntransfer-encoding: gzip, chunked\r\n\r\n0\r\n\r\n", 200, b"", true);
+    }
+
+    #[test]
+    fn chunked_body_with_extensions_and_trailers() {
+        assert_done(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n0;last\r\nx-trailer: 1\r\nx-other: 2\r\n\r\n", 200, b"hello", true);
+    }
+
+    #[test]
+    fn truncated_chunk() {
+        assert_malformed(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n10\r\nhello", "connection closed");
+        assert_malformed(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n", "connection closed");
+        assert_malformed(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n0\r\nx-trailer: 1\r\n", "connection closed");
+    }
+
+    #[test]
+    fn invalid_chunks() {
+        assert_malformed(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n", "invalid chunk size");
+        assert_malformed(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n2\r\nhello\r\n0\r\n\r\n", "not followed by crlf");
+        assert_malformed(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\nffffffffffffffff\r\nhello\r\n", "exceeds max response length");
+        let mut long_line = b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n".to_vec();
+        long_line.extend(vec![b'1'; MAX_CHUNK_LINE_LEN + 1]);
+        assert!(matches!(after_read(&mut long_line, false), Some(Attempt::Malformed(..))));
+    }
+
+    #[test]
+    fn conflicting_framing() {
+        assert_malformed(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\ncontent-length: 6\r\n\r\nhello!", "conflicting content-length");
+        assert_malformed(b"HTTP/1.1 200 OK\r\ncontent-length: five\r\n\r\nhello", "invalid content-length");
+        assert_malformed(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n", "both transfer-encoding");
+        assert_malformed(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhello", "connection closed");
+    }
+
+    #[test]
+    fn missing_or_invalid_status_line() {
+        assert_malformed(b"content-length: 0\r\n\r\n", "invalid response head");
+        assert_malformed(b"garbage\r\n\r\n", "invalid response head");
+        assert_malformed(b"HTTP/1.1 abc OK\r\n\r\n", "invalid response head");
+        assert_malformed(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n", "connection closed");
+    }
+
+    #[test]
+    fn oversized_head() {
+        let mut buf = b"HTTP/1.1 200 OK\r\n".to_vec();
+        while buf.len() <= MAX_HEAD_LEN {
+            buf.extend_from_slice(b"x-padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n");
+        }
+        assert!(matches!(after_read(&mut buf, false), Some(Attempt::Malformed(ref msg, _)) if msg.contains("head exceeds")));
+    }
+
+    #[test]
+    fn connection_close() {
+        assert_done(b"HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 2\r\n\r\nok", 200, b"ok", false);
+        assert_done(b"HTTP/1.1 200 OK\r\nConnection: Close\r\ncontent-length: 2\r\n\r\nok", 200, b"ok", false);
+        assert_done(b"HTTP/1.1 200 OK\r\nconnection: keep-alive\r\ncontent-length: 2\r\n\r\nok", 200, b"ok", true);
+        // without content-length or chunked encoding, the body ends when the connection does
+        assert_done(b"HTTP/1.1 200 OK\r\nconnection: close\r\n\r\nhello", 200, b"hello", false);
+        let mut buf = b"HTTP/1.1 200 OK\r\n\r\nhel".to_vec();
+        assert!(after_read(&mut buf, false).is_none());
+    }
+
+    #[test]
+    fn truncated_responses_never_panic() {
+        let responses: &[&[u8]] = &[
+            b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello",
+            b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5;x=y\r\nhello\r\n0\r\nx-trailer: 1\r\n\r\n",
+            b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n",
+        ];
+        for raw in responses {
+            for end in 0..raw.len() {
+                let mut buf = raw[..end].to_vec();
+                match after_read(&mut buf, true) {
+                    Some(Attempt::Malformed(..)) | Some(Attempt::Done { .. }) => {}
+                    _ => panic!("{:?} truncated at {}: unexpected outcome", String::from_utf8_lossy(raw), end),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rendered_request_bytes() {
+        let req = fitbod::api::ListWorkoutsRequest::from(uuid::Uuid::nil());
+        let rendered = render_request(&request_template(), "/api/v1/workouts/list", &req, &[0; 64], ConnectionMode::KeepAlive);
+        let split = rendered.find("\r\n\r\n").expect("no blank line after the head");
+        let (head, body) = (&rendered[..split], &rendered[split + 4..]);
+        assert!(head.starts_with("POST /api/v1/workouts/list HTTP/1.1\r\n"));
+        assert!(!head.replace("\r\n", "").contains('\n'), "bare newline in head: {:?}", head);
+        assert!(head.split("\r\n").any(|line| line == "connection: keep-alive"));
+        let content_length: usize = head.split("\r\n")
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .expect("no content-length header")
+            .parse()
+            .unwrap();
+        assert_eq!(body, serde_json::to_string(&req).unwrap());
+        assert_eq!(body.len(), content_length);
+    }
+
+    /// sends a request to a server that replies with `response` (written in two halves) and
+    /// closes the connection
+    fn request_against(response: &'static [u8]) -> Result<Vec<u8>, ApiError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf);
+            let (a, b) = response.split_at(response.len() / 2);
+            stream.write_all(a).unwrap();
+            stream.flush().unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            stream.write_all(b).unwrap();
+        });
+        let opts = HttpOpts::from_iter_safe(&["test", "--connect", &addr.to_string(), "--read-timeout", "2s"]).unwrap();
+        let metrics = crate::metrics::MetricsOpts::from_iter_safe(&["test", "--metrics-sink", "none"]).unwrap();
+        let mut client = ApiClient::new(&opts, MetricsSink::new(&metrics), Default::default());
+        let resp = client.request("/api/v1/workouts/list", &fitbod::api::ListWorkoutsRequest::from(uuid::Uuid::nil()), &[0; 64]);
+        server.join().unwrap();
+        resp
+    }
+
+    #[test]
+    fn malformed_output_is_an_error() {
+        assert_eq!(request_against(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").unwrap(), b"ok");
+        assert!(matches!(request_against(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n10\r\nhello"), Err(ApiError::Malformed(_))));
+        assert!(matches!(request_against(b"not http at all\r\n\r\n"), Err(ApiError::Malformed(_))));
+        assert!(matches!(request_against(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 4\r\n\r\nbusy"), Err(ApiError::Status(503, ref body)) if body == b"busy"));
+    }
+}
@@ -0,0 +1,530 @@
+//! open-loop load generation for stress-test
+//!
+//! by default the manager hands out jobs as fast as workers accept them (closed-loop), so a
+//! slow server also lowers the offered load. with `--rate`, jobs are instead scheduled on a
+//! fixed timeline, and each job carries the time it was supposed to be sent, which is what
+//! its latency is measured from.
+//!
+//! the rate can also vary over time according to a load profile (`--profile`), a sequence
+//! of stages such as linear ramps, staircase steps, and constant phases (short spikes, long
+//! soaks, or idle periods at a rate of 0).
+
+use std::time::*;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::fmt;
+use structopt::StructOpt;
+
+use crate::StressTestJob;
+use crate::engine::JobSender;
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct LoadOpts {
+    /// open-loop mode: schedule jobs at this constant rate (req/s) regardless of how
+    /// quickly the server responds. latency is measured from each job's scheduled send time.
+    /// by default, jobs are handed out as fast as workers accept them.
+    #[structopt(long, conflicts_with_all = &["profile", "profile-file"], parse(try_from_str = parse_rate))]
+    pub rate: Option<f64>,
+
+    /// open-loop mode with a rate that changes over time. comma-separated stages, run in order:
+    ///
+    ///   hold:<rate>:<duration>              constant rate
+    ///   ramp:<from>-<to>:<duration>         linear ramp
+    ///   steps:<from>-<to>/<step>:<duration> staircase, holding each step for <duration>
+    ///
+    /// e.g. "ramp:500-6000:10m,hold:6000:30m,hold:12000:30s,hold:0:1m,hold:6000:4h". rates
+    /// are req/s, durations are like "90s", "10m", "1h 30m". nothing is sent while the rate
+    /// is 0. the run ends when the profile completes.
+    #[structopt(long, conflicts_with = "profile-file", verbatim_doc_comment)]
+    pub profile: Option<Profile>,
+
+    /// like --profile, but read from a file with one stage per line (blank lines and
+    /// lines starting with # are ignored)
+    #[structopt(long)]
+    pub profile_file: Option<PathBuf>,
+
+    /// in open-loop mode, new jobs are dropped (not generated) while the harness is behind
+    /// schedule by more than this
+    #[structopt(long, default_value = "1s", parse(try_from_str = humantime::parse_duration))]
+    pub max_schedule_lag: Duration,
+}
+
+impl LoadOpts {
+    /// the rate schedule selected by --rate, --profile or --profile-file, if any
+    pub fn profile(&self) -> Option<Profile> {
+        if let Some(rate) = self.rate {
+            return Some(Profile::constant(rate))
+        }
+        if let Some(path) = self.profile_file.as_ref() {
+            return Some(Profile::load(path))
+        }
+        self.profile.clone()
+    }
+}
+
+/// rates above 0 but below this are rounded up, so that a ramp starting from zero doesn't
+/// wait seconds for its first job
+const MIN_RATE: f64 = 1.0;
+
+/// how often an idle stage (rate of 0) checks whether it has ended
+const IDLE_TICK: Duration = Duration::from_millis(1);
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("invalid rate '{}' (expected a number of req/s above 0)", s)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StageKind {
+    /// constant rate. a rate of 0 is an idle period.
+    Hold { rate: f64 },
+    Ramp { from: f64, to: f64 },
+    /// staircase from `from` to `to`, changing by `step` every `duration` of the stage
+    Steps { from: f64, to: f64, step: f64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub kind: StageKind,
+    /// for `Steps`, this is the duration of each step. `None` means run indefinitely.
+    pub duration: Option<Duration>,
+}
+
+impl Stage {
+    fn n_steps(&self) -> u32 {
+        match self.kind {
+            StageKind::Steps { from, to, step } => ((to - from).abs() / step).floor() as u32 + 1,
+            _ => 1,
+        }
+    }
+
+    /// total time spent in this stage
+    pub fn total_duration(&self) -> Option<Duration> {
+        self.duration.map(|d| d * self.n_steps())
+    }
+
+    /// target rate `elapsed` after the start of the stage
+    pub fn rate_at(&self, elapsed: Duration) -> f64 {
+        match self.kind {
+            StageKind::Hold { rate } => rate,
+
+            StageKind::Ramp { from, to } => {
+                let frac = match self.duration {
+                    Some(d) if d > Duration::from_secs(0) => (elapsed.as_secs_f64() / d.as_secs_f64()).min(1.0),
+                    _ => 1.0,
+                };
+                from + (to - from) * frac
+            }
+
+            StageKind::Steps { from, to, step } => {
+                let i = match self.duration {
+                    Some(d) if d > Duration::from_secs(0) => (elapsed.as_secs_f64() / d.as_secs_f64()).floor(),
+                    _ => 0.0,
+                };
+                let i = i.min((self.n_steps() - 1) as f64);
+                from + (to - from).signum() * step * i
+            }
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mut parts = s.splitn(3, ':');
+        let (name, rates, duration) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(rates), Some(duration)) => (name, rates, duration),
+            _ => return Err(format!("invalid stage '{}' (expected <kind>:<rate(s)>:<duration>)", s)),
+        };
+
+        let rate = |x: &str| -> Result<f64, String> {
+            match x.trim().parse::<f64>() {
+                Ok(r) if r >= 0.0 && r.is_finite() => Ok(r),
+                _ => Err(format!("invalid rate '{}' in stage '{}'", x, s)),
+            }
+        };
+        let range = |x: &str| -> Result<(f64, f64), String> {
+            let mut it = x.splitn(2, '-');
+            match (it.next(), it.next()) {
+                (Some(from), Some(to)) => Ok((rate(from)?, rate(to)?)),
+                _ => Err(format!("invalid range '{}' in stage '{}' (expected <from>-<to>)", x, s)),
+            }
+        };
+        let duration = humantime::parse_duration(duration.trim())
+            .map_err(|e| format!("invalid duration '{}' in stage '{}': {}", duration, s, e))?;
+
+        let kind = match name.trim() {
+            "hold" => StageKind::Hold { rate: rate(rates)? },
+            "ramp" => {
+                let (from, to) = range(rates)?;
+                StageKind::Ramp { from, to }
+            }
+            "steps" => {
+                let mut it = rates.splitn(2, '/');
+                let (from, to) = range(it.next().unwrap())?;
+                let step = match it.next().map(rate) {
+                    Some(Ok(step)) if step > 0.0 => step,
+                    _ => return Err(format!("invalid stage '{}' (expected steps:<from>-<to>/<step>:<duration>)", s)),
+                };
+                StageKind::Steps { from, to, step }
+            }
+            other => return Err(format!("invalid stage kind '{}' (expected hold, ramp or steps)", other)),
+        };
+        Ok(Stage { kind, duration: Some(duration) })
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            StageKind::Hold { rate } => write!(f, "hold:{}", rate)?,
+            StageKind::Ramp { from, to } => write!(f, "ramp:{}-{}", from, to)?,
+            StageKind::Steps { from, to, step } => write!(f, "steps:{}-{}/{}", from, to, step)?,
+        }
+        match self.duration {
+            Some(d) => write!(f, ":{}", humantime::format_duration(d)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// sequence of stages describing the target rate over the course of a run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub stages: Vec<Stage>,
+}
+
+impl Profile {
+    /// the --rate profile: a single stage that never ends
+    pub fn constant(rate: f64) -> Self {
+        Profile { stages: vec![Stage { kind: StageKind::Hold { rate }, duration: None }] }
+    }
+
+    pub fn load(path: &Path) -> Self {
+        let text = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("failed to read --profile-file {}: {}", path.display(), e));
+        let stages = text.lines()
+            .map(|line| line.trim())
+            .filter(|line| ! line.is_empty() && ! line.starts_with('#'))
+            .map(|line| line.parse::<Stage>().unwrap_or_else(|e| panic!("{}: {}", path.display(), e)))
+            .collect::<Vec<_>>();
+        assert!( ! stages.is_empty(), "--profile-file {} has no stages", path.display());
+        Profile { stages }
+    }
+
+    pub fn total_duration(&self) -> Option<Duration> {
+        self.stages.iter().map(|x| x.total_duration()).sum()
+    }
+
+    /// the stage active at `elapsed` since the start of the run, and the time since the start
+    /// of that stage. `None` once the profile has completed.
+    pub fn stage_at(&self, elapsed: Duration) -> Option<(&Stage, Duration)> {
+        let mut stage_start = Duration::from_secs(0);
+        for stage in self.stages.iter() {
+            match stage.total_duration() {
+                Some(d) if elapsed >= stage_start + d => stage_start += d,
+                _ => return Some((stage, elapsed - stage_start)),
+            }
+        }
+        None
+    }
+
+    /// target rate at `elapsed` since the start of the run. `None` once the profile has completed.
+    pub fn rate_at(&self, elapsed: Duration) -> Option<f64> {
+        self.stage_at(elapsed).map(|(stage, t)| stage.rate_at(t))
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let stages = s.split(',')
+            .filter(|x| ! x.trim().is_empty())
+            .map(|x| x.parse())
+            .collect::<Result<Vec<Stage>, String>>()?;
+        if stages.is_empty() {
+            return Err("load profile has no stages".to_string())
+        }
+        Ok(Profile { stages })
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, stage) in self.stages.iter().enumerate() {
+            if i > 0 { f.write_str(",")?; }
+            write!(f, "{}", stage)?;
+        }
+        Ok(())
+    }
+}
+
+/// outcome of waiting for the next slot on the timeline
+pub enum Slot {
+    /// a job should be sent, with this as its intended send time
+    Send(Instant),
+    /// the harness is too far behind schedule; skip this job
+    Dropped,
+    /// the rate is 0 right now; nothing to send
+    Idle,
+    /// the load profile has completed
+    Done,
+}
+
+/// timeline used to pace the manager loop in open-loop mode.
+///
+/// jobs that no worker can accept yet are held in a backlog (oldest first) rather than
+/// blocking the manager, so the timeline keeps advancing when the server slows down.
+pub struct Schedule {
+    profile: Profile,
+    max_lag: Duration,
+    start: Instant,
+    next: Instant,
+    backlog: VecDeque<StressTestJob>,
+    next_worker: usize,
+    /// multiplier applied to the profile's rate (changed from the --tui dashboard)
+    rate_scale: f64,
+    /// set while job dispatch is paused; the timeline resumes where it left off
+    paused_at: Option<Instant>,
+    /// jobs skipped because the harness was too far behind schedule
+    pub n_dropped: usize,
+}
+
+impl Schedule {
+    pub fn new(profile: Profile, max_lag: Duration) -> Self {
+        let start = Instant::now();
+        Self {
+            profile,
+            max_lag,
+            start,
+            next: start,
+            backlog: VecDeque::new(),
+            next_worker: 0,
+            rate_scale: 1.0,
+            paused_at: None,
+            n_dropped: 0,
+        }
+    }
+
+    /// waits until the next slot on the timeline (feeding backlogged jobs to workers in the
+    /// meantime). while the rate is 0, returns `Slot::Idle` after a short wait instead.
+    pub fn next_slot(&mut self, txs: &[JobSender]) -> Slot {
+        let rate = match self.profile.rate_at(self.next - self.start) {
+            Some(rate) if rate * self.rate_scale > 0.0 => (rate * self.rate_scale).max(MIN_RATE),
+            Some(_) => {
+                self.flush(txs);
+                std::thread::sleep(IDLE_TICK);
+                self.next = self.next.max(Instant::now());
+                return Slot::Idle
+            }
+            None => return Slot::Done,
+        };
+
+        loop {
+            self.flush(txs);
+            let now = Instant::now();
+            if now >= self.next { break }
+            std::thread::sleep((self.next - now).min(Duration::from_millis(1)));
+        }
+
+        let slot = self.next;
+        let lag = self.lag();
+        self.next += Duration::from_secs_f64(1.0 / rate);
+
+        if lag > self.max_lag {
+            self.n_dropped += 1;
+            return Slot::Dropped
+        }
+        Slot::Send(slot)
+    }
+
+    /// target rate right now (req/s)
+    pub fn target_rate(&self) -> f64 {
+        self.profile.rate_at(self.now() - self.start).unwrap_or(0.0) * self.rate_scale
+    }
+
+    /// the stage of the profile that is active right now
+    pub fn current_stage(&self) -> Option<&Stage> {
+        self.profile.stage_at(self.now() - self.start).map(|(stage, _)| stage)
+    }
+
+    pub fn rate_scale(&self) -> f64 {
+        self.rate_scale
+    }
+
+    /// multiplies the rate of the rest of the run by `factor`
+    pub fn scale_rate(&mut self, factor: f64) {
+        self.rate_scale *= factor;
+    }
+
+    /// stops the timeline. `next_slot` must not be called until `resume`.
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(Instant::now());
+        }
+    }
+
+    /// restarts the timeline where it was paused, so the time spent paused is skipped
+    /// rather than made up for
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            let paused_for = Instant::now().saturating_duration_since(paused_at);
+            self.start += paused_for;
+            self.next += paused_for;
+        }
+    }
+
+    /// queues `job` behind any backlogged jobs and sends as many as workers will accept
+    pub fn push(&mut self, job: StressTestJob, txs: &[JobSender]) {
+        self.backlog.push_back(job);
+        self.flush(txs);
+    }
+
+    /// number of jobs waiting for a free worker
+    pub fn backlog(&self) -> usize {
+        self.backlog.len()
+    }
+
+    /// how far behind schedule the harness is, i.e. how long the oldest job that has not
+    /// been handed to a worker has been waiting
+    pub fn lag(&self) -> Duration {
+        let oldest = self.backlog.front()
+            .and_then(|job| job.scheduled())
+            .unwrap_or(self.next);
+        self.now().saturating_duration_since(oldest)
+    }
+
+    /// blocks until every backlogged job has been accepted by a worker
+    pub fn drain(&mut self, txs: &[JobSender]) {
+        for job in self.backlog.drain(..) {
+            txs[self.next_worker % txs.len()].send(job);
+            self.next_worker += 1;
+        }
+    }
+
+    /// sends as many backlogged jobs as workers will accept
+    pub fn flush(&mut self, txs: &[JobSender]) {
+        'flush: while let Some(mut job) = self.backlog.pop_front() {
+            for _ in 0..txs.len() {
+                let tx = &txs[self.next_worker % txs.len()];
+                self.next_worker += 1;
+                match tx.try_send(job) {
+                    None => continue 'flush,
+                    Some(j) => job = j,
+                }
+            }
+            // every worker's queue is full
+            self.backlog.push_front(job);
+            break
+        }
+    }
+
+    /// current time on the timeline (which stands still while paused)
+    fn now(&self) -> Instant {
+        self.paused_at.unwrap_or_else(Instant::now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn stage(s: &str) -> Stage {
+        s.parse().unwrap_or_else(|e| panic!("{}: {}", s, e))
+    }
+
+    #[test]
+    fn parses_each_stage_kind() {
+        assert_eq!(stage("hold:6000:30m"), Stage { kind: StageKind::Hold { rate: 6000.0 }, duration: Some(secs(1800)) });
+        assert_eq!(stage("ramp:500-6000:10m"), Stage { kind: StageKind::Ramp { from: 500.0, to: 6000.0 }, duration: Some(secs(600)) });
+        assert_eq!(stage("steps:1000-4000/1000:1m"),
+            Stage { kind: StageKind::Steps { from: 1000.0, to: 4000.0, step: 1000.0 }, duration: Some(secs(60)) });
+        assert_eq!(stage(" hold : 0.5 : 1h 30m "), Stage { kind: StageKind::Hold { rate: 0.5 }, duration: Some(secs(5400)) });
+        assert_eq!(stage("hold:0:1m"), Stage { kind: StageKind::Hold { rate: 0.0 }, duration: Some(secs(60)) });
+    }
+
+    #[test]
+    fn rejects_invalid_stages() {
+        for s in &[
+            "hold:100",
+            "hold",
+            "",
+            "soak:6000:4h",
+            "spike:12000:30s",
+            "hold:-1:1m",
+            "hold:fast:1m",
+            "hold:inf:1m",
+            "hold:100:soon",
+            "ramp:100:1m",
+            "ramp:100-:1m",
+            "steps:100-400:1m",
+            "steps:100-400/0:1m",
+            "steps:100-400/x:1m",
+        ] {
+            assert!(s.parse::<Stage>().is_err(), "'{}' parsed", s);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let profile: Profile = "ramp:500-6000:10m,hold:6000:30m,hold:0:1m,steps:1000-4000/1000:1m".parse().unwrap();
+        assert_eq!(profile.to_string().parse::<Profile>().unwrap(), profile);
+    }
+
+    #[test]
+    fn rejects_empty_profile() {
+        assert!("".parse::<Profile>().is_err());
+        assert!(" , ".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn rejects_zero_constant_rate() {
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-5").is_err());
+        assert_eq!(parse_rate("250"), Ok(250.0));
+    }
+
+    #[test]
+    fn rate_follows_stages() {
+        let profile: Profile = "ramp:0-1000:10s,hold:2000:5s,hold:0:5s,steps:100-400/100:2s".parse().unwrap();
+        assert_eq!(profile.total_duration(), Some(secs(10 + 5 + 5 + 4 * 2)));
+
+        assert_eq!(profile.rate_at(secs(0)), Some(0.0));
+        assert_eq!(profile.rate_at(secs(5)), Some(500.0));
+        assert_eq!(profile.rate_at(Duration::from_millis(9_999)).map(|x| x.round()), Some(1000.0));
+        assert_eq!(profile.rate_at(secs(10)), Some(2000.0));
+        assert_eq!(profile.rate_at(secs(14)), Some(2000.0));
+        assert_eq!(profile.rate_at(secs(15)), Some(0.0));
+        assert_eq!(profile.rate_at(secs(20)), Some(100.0));
+        assert_eq!(profile.rate_at(secs(21)), Some(100.0));
+        assert_eq!(profile.rate_at(secs(22)), Some(200.0));
+        assert_eq!(profile.rate_at(secs(27)), Some(400.0));
+        assert_eq!(profile.rate_at(secs(28)), None);
+    }
+
+    #[test]
+    fn steps_down_and_partial_last_step() {
+        // 1000, 700, 400, 100 - a step past `to` isn't taken
+        let profile: Profile = "steps:1000-0/300:1s".parse().unwrap();
+        let rates: Vec<Option<f64>> = (0..5).map(|i| profile.rate_at(secs(i))).collect();
+        assert_eq!(rates, vec![Some(1000.0), Some(700.0), Some(400.0), Some(100.0), None]);
+    }
+
+    #[test]
+    fn constant_profile_never_ends() {
+        let profile = Profile::constant(250.0);
+        assert_eq!(profile.total_duration(), None);
+        assert_eq!(profile.rate_at(secs(0)), Some(250.0));
+        assert_eq!(profile.rate_at(secs(365 * 24 * 3600)), Some(250.0));
+    }
+}
@@ -1,9 +1,9 @@
 use std::time::*;
-use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex, RwLock};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex};
 use std::path::*;
-use std::io::{self, prelude::*};
+use std::collections::VecDeque;
+use std::io::prelude::*;
 use std::convert::TryInto;
-use std::net::SocketAddr;
 use rayon::prelude::*;
 use serde::{Serialize, Deserialize};
 use hashbrown::{HashMap, HashSet};
@@ -15,14 +15,75 @@ use uuid::Uuid;
 use itertools::Itertools;
 use chrono_tz::US::Pacific;
 use structopt::StructOpt;
-use influx_writer::{InfluxWriter, measure};
+
+mod http;
+mod engine;
+mod load;
+mod stats;
+mod report;
+mod compare;
+mod slo;
+mod metrics;
+mod prometheus;
+mod dashboard;
+mod resources;
+mod oracle;
+mod history;
+mod dbcheck;
+mod authtest;
+
+use http::{ApiClient, ApiError, AsyncApiClient, HttpOpts};
+use engine::{Engine, JobSender};
+use load::{LoadOpts, Schedule, Slot};
+use stats::{Latencies, LatencyShard};
+use report::{EndpointStats, IntervalStats, RunReport};
+use slo::{SloMonitor, SloOpts};
+use metrics::{MetricsOpts, MetricsSink, RunSnapshot};
+use dashboard::{Command, Dashboard};
+use resources::{ResourceMonitor, ResourceOpts};
+use oracle::ListWindow;
+use history::History;
 
 const API_REQUEST: &str = include_str!("../templates/api-request.tera");
 
+/// stress-test exit status when the run was aborted by an error threshold (e.g. --max-timeouts)
+const EXIT_ABORTED: i32 = 1;
+
+/// stress-test exit status when the final check found users whose workouts don't match what
+/// was written
+const EXIT_VERIFICATION_FAILED: i32 = 2;
+
+/// compare-runs exit status when the candidate run regressed vs. the baseline
+const EXIT_REGRESSION: i32 = 3;
+
+/// stress-test exit status when one or more --slo objectives were not met
+const EXIT_SLO_VIOLATED: i32 = 4;
+
+/// auth-test exit status when the server accepted (or mishandled) a request it should have
+/// rejected
+const EXIT_AUTH_FAILED: i32 = 5;
+
+/// for counts of threads, tasks, etc., where 0 would leave nothing to do the work
+fn parse_nonzero(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid value '{}' (expected a whole number above 0)", s)),
+    }
+}
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(format!("invalid value '{}' (expected a probability between 0 and 1)", s)),
+    }
+}
+
 /// tools for testing fitbod api server
 ///
 /// note: this program does not handle *any* errors. that is "on purpose," because this is supposed
-/// to test the api server, so problems should cause a screeching halt to the program. 
+/// to test the api server, so problems should cause a screeching halt to the program. the
+/// exception is stress-test, which counts failures against an error budget (see --max-errors)
+/// so that a long soak test isn't thrown away over one transient failure.
 ///
 /// however, as this is being completed for the purpose of evaluation, please note that this style of
 /// code is (constant use of `.unwrap()`) is not idiomatic and would be bad practice for code
@@ -86,12 +147,14 @@ enum Opt {
         users_csv_path: PathBuf,
 
         /// number of threads that will simultaneously be inserting data via api
-        #[structopt(short = "j", long, default_value = "4")]
+        #[structopt(short = "j", long, default_value = "4", parse(try_from_str = parse_nonzero))]
         n_threads: usize,
 
-        /// api server address
-        #[structopt(short, long, default_value = "127.0.0.1:3030")]
-        connect: SocketAddr,
+        #[structopt(flatten)]
+        http: HttpOpts,
+
+        #[structopt(flatten)]
+        metrics: MetricsOpts,
     },
 
 
@@ -123,13 +186,15 @@ enum Opt {
     ///
     /// to begin with, workouts table will be truncated (unless in --read-only mode).
     ///
-    /// users will be selected randomly, and a job will be chosen: 80% probability read,
-    /// 20% probability write.
+    /// users will be selected randomly, and a job will be chosen: by default, 80% probability
+    /// read, 20% probability write (see --read-probability).
     ///
-    /// - read job will fetch last 25 workouts
+    /// - read job will fetch the user's workouts (the most recent --list-limit, if given),
+    ///   some of the time filtered by a random date window (see --window-probability)
     ///
-    /// - write job will insert 15 workouts, which will include 10 previously inserted
-    ///   workouts (except on first write) and 5 new workouts.
+    /// - write job will insert --write-size workouts (15 by default), which will include
+    ///   --write-resend previously inserted workouts (10 by default, except on first write)
+    ///   and the rest new workouts.
     ///
     /// the workouts in --workouts-csv-path will be used as templates. a given randomly
     /// generated user will be assigned one of the templates from that file and his writes
@@ -147,8 +212,10 @@ enum Opt {
     /// then (read or write) jobs will be assigned for each of the sampled users.
     ///
     /// a user may be sampled more than once in a given batch. to prevent race conditions
-    /// between threads in terms of the read jobs validating the results, the state of which
-    /// workouts has been inserted for a given user is behind a mutex (rw lock actually).
+    /// between threads in terms of the read jobs validating the results, each user has a
+    /// lock that is held for the duration of each of their requests, so requests for the same
+    /// user never overlap. with --unlocked, requests for the same user run concurrently, and
+    /// reads are checked afterwards from the --history-path history instead.
     ///
     /// in --read-only mode, everything is the same except that all jobs are read jobs, and there
     /// is no validating the results against what workout rows are known to have been written.
@@ -160,9 +227,52 @@ enum Opt {
     /// will generate simultaneously. each thread proceeds synchronosly. a manager thread is in
     /// charge of assigning jobs to the worker threads, and keeps track of the state of each user.
     ///
-    /// program will continue until ctrl-c (kill signal) prompts exit. at that time, there will be
-    /// a final check between the state of users on db vs. what we expect based on writes
-    /// executed against db server. this check will be skipped in --read-only mode.
+    /// with --engine async, workers are instead --virtual-users tokio tasks running on a runtime
+    /// with --n-threads worker threads. each virtual user holds its own connection and sends one
+    /// request at a time, so concurrency is set by --virtual-users rather than thread count.
+    ///
+    /// worker threads use blocking socket calls (with deadlines), so a thread waiting on the api
+    /// server is asleep rather than spinning. it is reasonable to run with hundreds or thousands
+    /// of --n-threads to get that many requests in flight at once. the cpu usage of this process
+    /// is printed along with the other stats as a sanity check that the harness isn't the
+    /// bottleneck.
+    ///
+    /// latency percentiles (per endpoint and status) for the last interval are printed under
+    /// the stats line, and a full percentile table for the whole run is printed at exit.
+    ///
+    /// by default, each worker thread reuses http/1.1 keep-alive connections to the api server
+    /// (see --connection-mode and --pool-size). use --connection-mode per-request to open a new
+    /// connection for every request instead.
+    ///
+    /// requests that exceed one of the connect/write/read deadlines are counted as timeouts
+    /// rather than halting the program. a write that timed out may or may not have been applied
+    /// by the server, so its workouts are accepted (but not required) in subsequent read checks
+    /// until they are observed.
+    ///
+    /// by default, jobs are assigned as fast as workers accept them, so the offered load falls
+    /// when the api server slows down. with --rate, jobs are instead scheduled at a constant
+    /// rate, and latency is measured from each job's scheduled send time. jobs that can't be
+    /// assigned right away wait in a backlog; if the harness falls more than
+    /// --max-schedule-lag behind, jobs are dropped. both are printed with the other stats.
+    ///
+    /// --profile (or --profile-file) varies the rate over time, e.g. ramping up to find the
+    /// point where latency degrades, then holding there for a soak. the run ends (with the
+    /// usual final check) once the profile completes.
+    ///
+    /// program will continue until ctrl-c (kill signal) prompts exit, or until one of --duration,
+    /// --max-requests or --max-workouts-inserted is reached. at that time, there will be a final
+    /// check between the state of users on db vs. what we expect based on writes executed
+    /// against db server. this check will be skipped in --read-only mode.
+    ///
+    /// failures don't stop the run: failed requests are counted by endpoint and class, and a
+    /// list response that doesn't match the workouts written (a failed read check) is counted
+    /// as well, with the details printed to stderr. use --max-timeouts, --max-errors or
+    /// --max-error-rate to abort the run once too many have occurred.
+    ///
+    /// exit status is 0 if the run completed and every read check passed, 1 if the run was
+    /// aborted (e.g. --max-errors), 2 if any read check failed (while running, in the final
+    /// check, in the --history-path check, or in the --verify-db check), and 4 if any --slo
+    /// was not met (either over a --slo-window while running, or over the whole run).
     ///
     StressTest {
         /// path of csv file provided by fitbot with example workout data
@@ -172,17 +282,49 @@ enum Opt {
         #[structopt(short = "u", long, default_value = "var/random-users.csv")]
         users_csv_path: PathBuf,
 
-        /// number of threads that will simultaneously be inserting data via api
-        #[structopt(short = "j", long, default_value = "4")]
+        /// number of threads that will simultaneously be inserting data via api (with
+        /// --engine async, the number of tokio runtime worker threads)
+        #[structopt(short = "j", long, default_value = "4", parse(try_from_str = parse_nonzero))]
         n_threads: usize,
 
+        /// threads: each of --n-threads os threads sends one request at a time.
+        /// async: each of --virtual-users tokio tasks sends one request at a time
+        #[structopt(long, default_value = "threads")]
+        engine: Engine,
+
+        /// number of concurrent virtual users (ignored unless --engine async)
+        #[structopt(long, default_value = "1024", parse(try_from_str = parse_nonzero))]
+        virtual_users: usize,
+
         /// number of users to assign jobs for in between shuffling
-        #[structopt(long, default_value = "1024")]
+        #[structopt(long, default_value = "1024", parse(try_from_str = parse_nonzero))]
         batch_size: usize,
 
-        /// api server address
-        #[structopt(short, long, default_value = "127.0.0.1:3030")]
-        connect: SocketAddr,
+        #[structopt(flatten)]
+        mix: JobMixOpts,
+
+        #[structopt(flatten)]
+        http: HttpOpts,
+
+        #[structopt(flatten)]
+        load: LoadOpts,
+
+        #[structopt(flatten)]
+        metrics: MetricsOpts,
+
+        #[structopt(flatten)]
+        stop: StopOpts,
+
+        #[structopt(flatten)]
+        slo: SloOpts,
+
+        #[structopt(flatten)]
+        resources: ResourceOpts,
+
+        /// write a json report of the run (parameters, request counts, latency percentiles,
+        /// per-interval stats, final check results) to this path when it ends
+        #[structopt(long)]
+        report_path: Option<PathBuf>,
 
         /// don't insert any data, only read it.
         ///
@@ -190,6 +332,35 @@ enum Opt {
         /// to /api/v1/workouts/list
         #[structopt(long)]
         read_only: bool,
+
+        #[structopt(flatten)]
+        budget: ErrorBudgetOpts,
+
+        /// record every request, with the times it was sent and completed, to this file (one
+        /// json object per line). the history is checked for consistency at the end of the run
+        /// (see check-history).
+        #[structopt(long)]
+        history_path: Option<PathBuf>,
+
+        /// don't hold the per-user lock for the duration of each request, so that reads and
+        /// writes for the same user run concurrently. reads can't be checked against exact
+        /// expected state while writes are in flight, so they are checked from the history
+        /// at the end of the run instead (the final check still runs as usual).
+        #[structopt(long, requires = "history-path", conflicts_with = "read-only")]
+        unlocked: bool,
+
+        /// after the final check, also compare each user's rows in the workouts table (via
+        /// DATABASE_URL) to the workouts written, and to what the final check's list request
+        /// returned. reports missing, extra and mutated rows, and users for whom the api and
+        /// the database disagree.
+        #[structopt(long, conflicts_with = "read-only")]
+        verify_db: bool,
+
+        /// show a full-screen dashboard instead of printing stats every second. keys: p or
+        /// space pauses/resumes job dispatch, + and - change the rate by 10% (with --rate or
+        /// --profile only), q stops the run.
+        #[structopt(long)]
+        tui: bool,
     },
 
     /// print example http request for /api/v1/workouts/list endpoint to stdout
@@ -241,6 +412,59 @@ enum Opt {
         /// workout duration in minutes
         duration: u32,
     },
+
+    /// compare two stress-test run reports (see --report-path), printing per-endpoint
+    /// throughput and latency percentile deltas. exits with status 3 if the candidate run
+    /// regressed beyond the thresholds below (and the difference is statistically significant).
+    ///
+    /// runs should use the same parameters (in particular, --rate or --profile, since
+    /// throughput in closed-loop mode depends on the server) for the comparison to be meaningful.
+    CompareRuns {
+        /// report of the baseline run (e.g. a stored report from the last release)
+        baseline: PathBuf,
+
+        /// report of the run being evaluated
+        candidate: PathBuf,
+
+        #[structopt(flatten)]
+        thresholds: compare::Thresholds,
+    },
+
+    /// check a history recorded by stress-test --history-path for consistency: no read returns
+    /// a workout before it was written, and none misses a workout whose write completed (or
+    /// that an earlier read returned) before it started. exits with status 2 if any
+    /// violations are found.
+    CheckHistory {
+        /// path of the history file
+        history_path: PathBuf,
+
+        /// print at most this many violations
+        #[structopt(long, default_value = "100")]
+        max_shown: usize,
+    },
+
+    /// send requests that the api server must reject - invalid signatures, signatures made with
+    /// another user's key, missing or duplicated auth headers, stale and future timestamps,
+    /// bodies altered after signing, requests for a user other than the signer - to both
+    /// endpoints, and check that each is rejected with the expected status, that no workouts
+    /// are included in the rejection, and that nothing is stored.
+    ///
+    /// two random users from --users-csv-path are used, and a few workouts are written for
+    /// each (with correctly signed requests) first. exits with status 5 if any check fails.
+    AuthTest {
+        #[structopt(short = "u", long, default_value = "var/example-users.csv")]
+        users_csv_path: PathBuf,
+
+        /// how far in the past (or future) the stale (or future) timestamps are
+        #[structopt(long, default_value = "1h", parse(try_from_str = humantime::parse_duration))]
+        timestamp_skew: Duration,
+
+        #[structopt(flatten)]
+        http: HttpOpts,
+
+        #[structopt(flatten)]
+        metrics: MetricsOpts,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -282,27 +506,369 @@ struct UserState {
     /// contains a set of correct `workout_id` values for this user.
     workouts: Arc<Vec<fitbod::Workout>>,
     workout_ids: Vec<Uuid>,
-    inserted: Arc<RwLock<HashSet<Uuid>>>,
+    inserted: Arc<tokio::sync::RwLock<Inserted>>,
     pos: usize,
 }
 
+impl UserState {
+    /// the workouts for the user's next write job: up to `write_size` of them, starting
+    /// `write_resend` before `pos`. returns them, and how many are new.
+    ///
+    /// workouts are counted as new by position rather than by whether they're in `inserted`,
+    /// since a write still queued (or in flight) hasn't been recorded there yet, and a second
+    /// write issued meanwhile would count the same workouts again.
+    fn next_write(&mut self, write_size: usize, write_resend: usize) -> (Vec<fitbod::Workout>, usize) {
+        let start = self.pos.saturating_sub(write_resend);
+        let end = (start + write_size).min(self.workouts.len());
+        let workouts = (start..end).map(|i| {
+            let mut w = self.workouts[i].clone();
+            w.user_id = self.user_id;
+            w.workout_id = self.workout_ids[i];
+            w
+        }).collect();
+        let n_new = end.saturating_sub(self.pos);
+        self.pos = self.pos.max(end);
+        (workouts, n_new)
+    }
+
+    /// workouts assigned to write jobs so far, sorted by `start_time`
+    fn written(&self) -> &[fitbod::Workout] {
+        &self.workouts[..self.pos.min(self.workouts.len())]
+    }
+}
+
+/// workouts written for a given user
+#[derive(Default)]
+struct Inserted {
+    /// workouts whose /api/v1/workouts/new request succeeded
+    confirmed: HashSet<Uuid>,
+    /// workouts whose /api/v1/workouts/new request timed out or got a malformed response,
+    /// which may or may not have been written. these are moved to `confirmed` once they
+    /// show up in a list response.
+    unconfirmed: HashSet<Uuid>,
+    /// every workout in `confirmed` or `unconfirmed`, as sent to the server
+    written: HashMap<Uuid, fitbod::Workout>,
+}
+
+impl Inserted {
+    /// returns number of workouts newly confirmed
+    fn confirm<'a, I: IntoIterator<Item = &'a fitbod::Workout>>(&mut self, workouts: I) -> usize {
+        let n_before = self.confirmed.len();
+        for workout in workouts {
+            self.unconfirmed.remove(&workout.workout_id);
+            self.confirmed.insert(workout.workout_id);
+            self.written.insert(workout.workout_id, workout.clone());
+        }
+        self.confirmed.len() - n_before
+    }
+
+    fn maybe_written<'a, I: IntoIterator<Item = &'a fitbod::Workout>>(&mut self, workouts: I) {
+        for workout in workouts {
+            if ! self.confirmed.contains(&workout.workout_id) {
+                self.unconfirmed.insert(workout.workout_id);
+                self.written.insert(workout.workout_id, workout.clone());
+            }
+        }
+    }
+
+    /// checks the workouts returned by /api/v1/workouts/list for `window` (requested by
+    /// `user_id`, whose workouts these are) against expected state (see `oracle`). every
+    /// field of each workout must come back exactly as it was written.
+    ///
+    /// unconfirmed workouts that were returned are assumed to have been written, and the rest
+    /// not, so the response must match exactly what `oracle::expected` predicts for the
+    /// confirmed workouts plus those. on success, returns number of unconfirmed workouts that
+    /// were present in the response (and are now confirmed); otherwise, the differences.
+    fn verify(&mut self, user_id: Uuid, items: &[fitbod::Workout], window: &ListWindow) -> Result<usize, Vec<oracle::Mismatch>> {
+        let observed: Vec<Uuid> = items.iter()
+            .map(|x| x.workout_id)
+            .filter(|x| self.unconfirmed.contains(x))
+            .collect();
+        let stored = self.confirmed.iter().chain(observed.iter()).map(|x| &self.written[x]);
+        let mismatches = oracle::diff(user_id, &oracle::expected(stored, window), items);
+        if ! mismatches.is_empty() {
+            return Err(mismatches)
+        }
+        let n_before = self.confirmed.len();
+        for workout_id in observed {
+            self.unconfirmed.remove(&workout_id);
+            self.confirmed.insert(workout_id);
+        }
+        Ok(self.confirmed.len() - n_before)
+    }
+
+    /// compares the user's `rows` in the workouts table to expected state (see `dbcheck`).
+    /// like `verify`, unconfirmed workouts are assumed to have been written if they were
+    /// stored, but they aren't moved to `confirmed`.
+    fn verify_stored(&self, user_id: Uuid, rows: &[fitbod::Workout]) -> Vec<oracle::Mismatch> {
+        let observed: HashSet<Uuid> = rows.iter()
+            .map(|x| x.workout_id)
+            .filter(|x| self.unconfirmed.contains(x))
+            .collect();
+        let stored = self.confirmed.iter().chain(observed.iter()).map(|x| &self.written[x]);
+        dbcheck::diff_stored(user_id, &oracle::expected(stored, &ListWindow::default()), rows)
+    }
+}
+
 enum StressTestJob {
     Read {
         user_id: Uuid,
+        /// filters sent in the list request
+        window: ListWindow,
         key: fitbod::auth::PrivateKey,
-        inserted: Arc<RwLock<HashSet<Uuid>>>,
+        inserted: Arc<tokio::sync::RwLock<Inserted>>,
+        /// intended send time (--rate mode only)
+        scheduled: Option<Instant>,
     },
 
     Write {
         user_id: Uuid,
         key: fitbod::auth::PrivateKey,
         workouts: Vec<fitbod::Workout>,
-        inserted: Arc<RwLock<HashSet<Uuid>>>,
+        inserted: Arc<tokio::sync::RwLock<Inserted>>,
+        /// intended send time (--rate mode only)
+        scheduled: Option<Instant>,
     },
 
     Exit,
 }
 
+impl StressTestJob {
+    fn scheduled(&self) -> Option<Instant> {
+        match self {
+            StressTestJob::Read { scheduled, .. } | StressTestJob::Write { scheduled, .. } => *scheduled,
+            StressTestJob::Exit => None,
+        }
+    }
+}
+
+/// why the stress-test manager loop stopped assigning jobs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    Signal,
+    ProfileComplete,
+    Duration,
+    MaxRequests,
+    MaxWorkoutsInserted,
+    MaxTimeouts,
+    MaxErrors,
+    MaxErrorRate,
+}
+
+impl StopReason {
+    /// whether the run was cut short by an error threshold, in which case the final check is
+    /// skipped. every other reason is a normal end to the run.
+    fn is_abort(&self) -> bool {
+        matches!(self, StopReason::MaxTimeouts | StopReason::MaxErrors | StopReason::MaxErrorRate)
+    }
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            StopReason::Signal => "exit signal received",
+            StopReason::ProfileComplete => "load profile complete",
+            StopReason::Duration => "--duration elapsed",
+            StopReason::MaxRequests => "--max-requests reached",
+            StopReason::MaxWorkoutsInserted => "--max-workouts-inserted reached",
+            StopReason::MaxTimeouts => "--max-timeouts exceeded",
+            StopReason::MaxErrors => "--max-errors exceeded",
+            StopReason::MaxErrorRate => "--max-error-rate exceeded",
+        })
+    }
+}
+
+// conditions that end a stress-test run (in addition to ctrl-c). each triggers the same
+// graceful shutdown as a signal: in-flight jobs finish, then the final check runs.
+#[derive(StructOpt, Debug, Clone)]
+struct StopOpts {
+    /// stop after running for this long (e.g. "30m", "2h")
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    duration: Option<Duration>,
+
+    /// stop after assigning this many jobs (requests) to workers
+    #[structopt(long)]
+    max_requests: Option<usize>,
+
+    /// stop once this many workouts are confirmed to have been inserted
+    #[structopt(long, conflicts_with = "read-only")]
+    max_workouts_inserted: Option<usize>,
+}
+
+// shape of the jobs stress-test sends, to model different app versions and sync behaviors
+#[derive(StructOpt, Serialize, Deserialize, Debug, Clone)]
+struct JobMixOpts {
+    /// probability that a job is a read (/api/v1/workouts/list) rather than a write. users
+    /// with no workouts left to write always get a read.
+    #[structopt(long, default_value = "0.8", parse(try_from_str = parse_probability))]
+    read_probability: f64,
+
+    /// number of workouts sent in each write job (fewer once a user runs out)
+    #[structopt(long, default_value = "15")]
+    write_size: usize,
+
+    /// number of previously inserted workouts re-sent in each write job (as an app re-syncing
+    /// its recent history would). must be less than --write-size.
+    #[structopt(long, default_value = "10")]
+    write_resend: usize,
+
+    /// `limit` sent in list requests. by default, none is sent and the server's default
+    /// applies, except in windowed reads (see --window-probability), which pick a random
+    /// limit half the time.
+    #[structopt(long)]
+    list_limit: Option<usize>,
+
+    /// probability that a read filters by a random date window (start and/or end) chosen
+    /// from the user's written workouts, to exercise those query paths. the response must be
+    /// exactly the workouts written in that window, newest first.
+    #[structopt(long, default_value = "0.25", parse(try_from_str = parse_probability))]
+    window_probability: f64,
+}
+
+impl JobMixOpts {
+    /// checks relations between options that can't be checked while parsing each one
+    fn validate(&self) -> Result<(), structopt::clap::Error> {
+        if self.write_resend >= self.write_size {
+            return Err(structopt::clap::Error::with_description(
+                &format!("--write-resend ({}) must be less than --write-size ({})", self.write_resend, self.write_size),
+                structopt::clap::ErrorKind::ValueValidation))
+        }
+        Ok(())
+    }
+}
+
+// how many failures a stress-test run tolerates before it is aborted. by default, there is
+// no limit: failed requests and read checks are counted by class and reported at the end,
+// but the run keeps going.
+#[derive(StructOpt, Debug, Clone)]
+struct ErrorBudgetOpts {
+    /// abort the run once more than this many requests have timed out (see
+    /// --connect-timeout, --write-timeout, --read-timeout)
+    #[structopt(long)]
+    max_timeouts: Option<usize>,
+
+    /// abort the run once more than this many failures have occurred: timeouts, connection
+    /// errors, non-2xx responses, malformed responses, invalid json, and read checks that
+    /// didn't match the workouts written
+    #[structopt(long)]
+    max_errors: Option<usize>,
+
+    /// abort the run if failures (as counted for --max-errors) exceed this percentage of
+    /// requests over the last --error-rate-window, e.g. 5 for 5%
+    #[structopt(long)]
+    max_error_rate: Option<f64>,
+
+    /// window --max-error-rate is checked over. the rate isn't checked until the run has
+    /// been going for this long.
+    #[structopt(long, default_value = "60s", parse(try_from_str = humantime::parse_duration))]
+    error_rate_window: Duration,
+}
+
+/// failure rate over a sliding window, from running totals sampled by the manager
+struct ErrorRate {
+    window: Duration,
+    /// --max-error-rate (percent)
+    max: f64,
+    /// (when, requests, failures)
+    samples: VecDeque<(Instant, usize, usize)>,
+}
+
+impl ErrorRate {
+    fn new(window: Duration, max: f64) -> Self {
+        Self { window, max, samples: VecDeque::new() }
+    }
+
+    /// like `update`, but only returns the failure rate if it's above the max
+    fn exceeded(&mut self, now: Instant, n_requests: usize, n_errors: usize) -> Option<f64> {
+        let max = self.max;
+        self.update(now, n_requests, n_errors).filter(|rate| *rate > max)
+    }
+
+    /// adds a sample, and returns the failure rate (percent) over the window. `None` until
+    /// there is a full window of samples, or if no requests were made in it.
+    fn update(&mut self, now: Instant, n_requests: usize, n_errors: usize) -> Option<f64> {
+        self.samples.push_back((now, n_requests, n_errors));
+        let window_start = now.checked_sub(self.window)?;
+        // keep the newest sample at or before the start of the window as the baseline
+        while self.samples.len() > 1 && self.samples[1].0 <= window_start {
+            self.samples.pop_front();
+        }
+        let (then, n_requests_then, n_errors_then) = *self.samples.front()?;
+        if then > window_start { return None }
+        let n_requests = n_requests - n_requests_then;
+        if n_requests == 0 { return None }
+        Some((n_errors - n_errors_then) as f64 / n_requests as f64 * 100.0)
+    }
+}
+
+/// counters updated by workers and reported by the manager
+#[derive(Default)]
+struct Counters {
+    /// number of workouts confirmed to have been inserted
+    n_inserted: AtomicUsize,
+    n_timeouts: AtomicUsize,
+    /// malformed responses and invalid json
+    n_malformed: AtomicUsize,
+    /// failed requests of every class (including timeouts and malformed responses)
+    n_errors: AtomicUsize,
+    /// failed requests by endpoint and failure class (see `ApiError::class`)
+    failures: Mutex<HashMap<(&'static str, String), usize>>,
+    /// read checks (while running, and in the final check) whose workouts didn't match
+    /// what was written
+    n_verification_failures: AtomicUsize,
+}
+
+impl Counters {
+    /// combines the counters with the totals kept by the manager
+    fn snapshot(&self, n_read: usize, n_write: usize, workouts_pending: usize) -> RunSnapshot {
+        RunSnapshot {
+            n_read,
+            n_write,
+            workouts_pending,
+            workouts_confirmed: self.n_inserted.load(Ordering::Relaxed),
+            n_timeouts: self.n_timeouts.load(Ordering::Relaxed),
+            n_malformed: self.n_malformed.load(Ordering::Relaxed),
+            n_errors: self.n_errors.load(Ordering::Relaxed),
+            n_verification_failures: self.n_verification_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_failure(&self, endpoint: &'static str, err: &ApiError) {
+        self.n_errors.fetch_add(1, Ordering::Relaxed);
+        match err {
+            ApiError::Timeout(_) => { self.n_timeouts.fetch_add(1, Ordering::Relaxed); }
+            ApiError::Malformed(_) | ApiError::Decode(_) => { self.n_malformed.fetch_add(1, Ordering::Relaxed); }
+            _ => {}
+        }
+        *self.failures.lock().unwrap().entry((endpoint, err.class())).or_default() += 1;
+    }
+
+    /// a list response that didn't match the workouts written for the user. counted with
+    /// the failed requests, against --max-errors and --max-error-rate, and classified by the
+    /// first mismatch (preferring corrupted workouts, which are the most specific).
+    fn record_verification_failure(&self, mismatches: &[oracle::Mismatch]) {
+        self.n_verification_failures.fetch_add(1, Ordering::Relaxed);
+        self.n_errors.fetch_add(1, Ordering::Relaxed);
+        let class = match mismatches.iter().find(|x| x.is_corruption()).or_else(|| mismatches.first()) {
+            Some(mismatch) => format!("read check failed: {}", mismatch.class()),
+            None => "read check failed".to_string(),
+        };
+        *self.failures.lock().unwrap().entry(("/api/v1/workouts/list", class)).or_default() += 1;
+    }
+
+    /// (endpoint, failure class, count), most frequent first
+    fn failure_counts(&self) -> Vec<report::FailureCount> {
+        let mut out: Vec<report::FailureCount> = self.failures.lock().unwrap().iter()
+            .map(|((endpoint, class), count)| report::FailureCount {
+                endpoint: endpoint.to_string(),
+                class: class.clone(),
+                count: *count,
+            }).collect();
+        out.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| (&a.endpoint, &a.class).cmp(&(&b.endpoint, &b.class))));
+        out
+    }
+}
+
 fn load_csv<T, P>(input_path: P) -> Vec<T>
     where T: for<'de> Deserialize<'de>,
           P: AsRef<Path>
@@ -494,6 +1060,7 @@ fn list_request(
         ctx.insert("body", &req_json);
         ctx.insert("sig", &sig);
         ctx.insert("timestamp", &timestamp_str);
+        ctx.insert("connection", "close");
 
         let http_req = tera.render("api-request", &ctx).unwrap(); //.replace("\n", "\r\n");
         print!("{}", http_req);
@@ -543,93 +1110,60 @@ fn new_workouts_request(users_csv_path: &Path, user_id: Option<Uuid>, email: Opt
     ctx.insert("body", &req_json);
     ctx.insert("sig", &sig);
     ctx.insert("timestamp", &timestamp_str);
+    ctx.insert("connection", "close");
 
     let http_req = tera.render("api-request", &ctx).unwrap(); //.replace("\n", "\r\n");
     print!("{}", http_req);
 }
 
-/// returns request body
-fn api_request<T>(addr: &SocketAddr, path: &str, req: &T, key: &fitbod::auth::PrivateKey, tera: &mut tera::Tera, influx: &InfluxWriter) -> Option<Vec<u8>>
-    where T: Serialize
-{
-    let req_json = serde_json::to_string(&req).unwrap();
-    let timestamp = Utc::now().timestamp();
-    let sig = fitbod::auth::sign_request(timestamp, &req_json, key);
-    let timestamp_str = timestamp.to_string();
-
-    let mut ctx = tera::Context::new();
-    ctx.insert("path", path);
-    ctx.insert("body", &req_json);
-    ctx.insert("sig", &sig);
-    ctx.insert("timestamp", &timestamp_str);
-    let http_req_str = tera.render("api-request", &ctx).unwrap();
-    let http_req = http_req_str.as_bytes();
-
-    let req_start = Instant::now();
-    let mut stream = std::net::TcpStream::connect(addr).unwrap();
-    stream.set_nonblocking(true).expect("send nonblocking");
-    stream.set_nodelay(true).expect("send nodelay");
-    let mut n_bytes_written = 0;
-    while n_bytes_written < http_req.len() {
-        match stream.write(&http_req[n_bytes_written..]) {
-            Ok(n) => n_bytes_written += n,
-            Err(e) if would_block(&e) => {}
-            Err(e) => return None,
-        }
-    }
-
-    let mut buf = [0u8; 16384];
-    let mut n_bytes_read = 0;
-
-    loop {
-        match stream.read(&mut buf[n_bytes_read..]) {
-            Ok(n) =>  n_bytes_read += n,
-            Err(e) if would_block(&e) => {},
-            Err(e) => return None,
-        }
-
-        let mut headers = Vec::<thhp::HeaderField>::with_capacity(8);
-        if let Ok(thhp::Complete((ref resp, body_offset))) = thhp::Response::parse(&buf[..n_bytes_read], &mut headers) {
-            let req_done = Instant::now();
-            let took = req_done.saturating_duration_since(req_start).as_nanos() as i64;
-            let endpoint = path;
-            let status = resp.status.to_string();
-
-            measure!(influx, api_req, t(endpoint), t(status), i(took), tm(Utc::now().timestamp_nanos()));
-
-            if ! (resp.status == 200 || resp.status == 204) {
-                eprintln!("***\nREQUEST:\n\n{}\n\nRESPONSE:\n\n{}\nbody.len()={}",
-                    http_req_str,
-                    std::str::from_utf8(&buf[..n_bytes_read]).unwrap(),
-                    req_json.len(),
-                );
-                return None
-            }
-            assert!(body_offset <= n_bytes_read);
-            return Some((&buf[body_offset..n_bytes_read]).to_vec())
-        } // TODO: add timeout here
-    }
-}
-
-fn stress_test(
-    workouts_csv_path: &Path,
-    users_csv_path: &Path,
+/// the stress-test subcommand's options (see `Opt::StressTest` for what each one does)
+struct StressTestConfig {
+    workouts_csv_path: PathBuf,
+    users_csv_path: PathBuf,
     n_threads: usize,
+    engine: Engine,
+    virtual_users: usize,
     batch_size: usize,
-    addr: SocketAddr,
+    mix: JobMixOpts,
+    http: HttpOpts,
+    load: LoadOpts,
+    metrics: MetricsOpts,
+    stop: StopOpts,
+    slo: SloOpts,
+    resources: ResourceOpts,
+    report_path: Option<PathBuf>,
     read_only: bool,
-) {
+    budget: ErrorBudgetOpts,
+    history_path: Option<PathBuf>,
+    unlocked: bool,
+    verify_db: bool,
+    tui: bool,
+}
+
+fn stress_test(config: StressTestConfig) {
+    let StressTestConfig {
+        workouts_csv_path, users_csv_path, n_threads, engine, virtual_users, batch_size, mix, http,
+        load, metrics, stop, slo, resources, report_path, read_only, budget, history_path, unlocked,
+        verify_db, tui,
+    } = config;
+    // checked up front, rather than failing after the run
+    let db_url = match verify_db {
+        true => Some(std::env::var("DATABASE_URL").expect("--verify-db requires DATABASE_URL")),
+        false => None,
+    };
+
     let begin = Instant::now();
+    let begin_utc = Utc::now();
     println!("beginning - make sure to restart the api server prior to this to re-cache user keys");
 
     let mut rng = thread_rng();
 
-    let mut users = load_private_keys(users_csv_path);
+    let mut users = load_private_keys(&users_csv_path);
     let n = users.len();
     println!("loaded private keys from --users-csv-path");
     users.shuffle(&mut rng);
 
-    let example_workouts = load_example_workouts(workouts_csv_path);
+    let example_workouts = load_example_workouts(&workouts_csv_path);
     println!("loaded example workouts");
 
     let mut workouts_by_user: HashMap<String, Vec<fitbod::Workout>> = Default::default();
@@ -677,63 +1211,55 @@ fn stress_test(
     let ix: Vec<usize> = (0..n).collect(); // sample indices instead of directly to access &mut user_states[i]
     println!("generated user engagement scores");
 
-    let mut txs = Vec::new();
+    let mut txs: Vec<JobSender> = Vec::new();
     let mut threads = Vec::new();
+    let mut tasks = Vec::new();
 
-    let influx = InfluxWriter::new("localhost", "fitbod");
-    let n_inserted = Arc::new(AtomicUsize::new(0));
-    let mut tera = tera::Tera::default();
-    tera.add_raw_template("api-request", API_REQUEST).unwrap();
+    let metrics = MetricsSink::new(&metrics);
+    let counters: Arc<Counters> = Default::default();
 
-    for i in 0..n_threads {
-        let (tx, rx) = crossbeam_channel::bounded(8);
-        txs.push(tx);
-        let mut tera = tera.clone();
-        let influx = influx.clone();
-        let addr = addr.clone();
-        let n_inserted = n_inserted.clone();
-        threads.push(std::thread::spawn(move || {
-            'event: loop {
-                match rx.recv() {
-                    Ok(StressTestJob::Exit) => break 'event,
-
-                    Ok(StressTestJob::Read { user_id, key, inserted }) if ! read_only => {
-                        let write_lock = inserted.write().unwrap();
-                        let req = fitbod::api::ListWorkoutsRequest::from(user_id);
-                        let resp = api_request(&addr, "/api/v1/workouts/list", &req, &key, &mut tera, &influx).unwrap();
-                        let resp: fitbod::api::ListWorkoutsResponse = serde_json::from_slice(&resp[..]).unwrap();
-                        let resp_wids: HashSet<Uuid> = resp.items.iter().map(|x| x.workout_id).collect();
-                        assert_eq!(write_lock.symmetric_difference(&resp_wids).count(), 0,
-                            "read check failed for user id {}\n{:#?}\n{:#?}", user_id, resp_wids, &*write_lock,
-                        );
-                        drop(write_lock);
-                    }
+    // each worker thread records latencies into its own shard. virtual users share one per
+    // runtime worker thread, since a histogram per virtual user would use a lot of memory.
+    let latency_shards: Vec<LatencyShard> = (0..n_threads).map(|_| Default::default()).collect();
 
-                    Ok(StressTestJob::Read { user_id, key, .. }) if read_only => {
-                        let req = fitbod::api::ListWorkoutsRequest::from(user_id);
-                        let _resp = api_request(&addr, "/api/v1/workouts/list", &req, &key, &mut tera, &influx).unwrap();
-                    }
+    let history: Option<Arc<History>> = history_path.as_deref().map(|path| Arc::new(History::create(path, begin)));
+    if unlocked {
+        println!("unlocked mode: requests for the same user may overlap, reads are checked from the history at the end");
+    }
 
-                    Ok(StressTestJob::Write { user_id, key, workouts, inserted }) => {
-                        assert!( ! read_only );
-                        let req = fitbod::api::NewWorkoutsRequest {
-                            user_id,
-                            items: workouts,
-                        };
-                        let mut write_lock = inserted.write().unwrap();
-                        let _resp = api_request(&addr, "/api/v1/workouts/new", &req, &key, &mut tera, &influx).unwrap();
-                        let n_before = write_lock.len();
-                        write_lock.extend(req.items.iter().map(|x| x.workout_id));
-                        let n_after = write_lock.len();
-                        drop(write_lock);
-                        n_inserted.fetch_add(n_after.saturating_sub(n_before), Ordering::Relaxed);
-                    }
+    let rt = match engine {
+        Engine::Threads => {
+            for shard in latency_shards.iter() {
+                let (tx, rx) = crossbeam_channel::bounded(8);
+                txs.push(JobSender::Thread(tx));
+                let client = ApiClient::new(&http, metrics.clone(), Arc::clone(shard));
+                let counters = Arc::clone(&counters);
+                let history = history.clone();
+                threads.push(std::thread::spawn(move || {
+                    engine::worker_thread(rx, client, counters, read_only, unlocked, history)
+                }));
+            }
+            None
+        }
 
-                    _ => todo!(),
-                }
+        Engine::Async => {
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(n_threads)
+                .enable_all()
+                .build()
+                .unwrap();
+            for i in 0..virtual_users {
+                let (tx, rx) = tokio::sync::mpsc::channel(2);
+                txs.push(JobSender::Task(tx));
+                let latencies = Arc::clone(&latency_shards[i % n_threads]);
+                let client = AsyncApiClient::new(&http, metrics.clone(), latencies);
+                tasks.push(rt.spawn(engine::virtual_user(rx, client, Arc::clone(&counters), read_only, unlocked, history.clone())));
             }
-        }));
-    }
+            println!("spawned {} virtual users", virtual_users.thousands_sep());
+            Some(rt)
+        }
+    };
+    let n_workers = txs.len();
 
     let term = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&term)).unwrap();
@@ -745,133 +1271,489 @@ fn stress_test(
     let mut n_pending_inserts = 0;
 
     let mut last_disp = Instant::now();
+    let mut last_cpu = process_cpu_time();
     let mut n_jobs_sent = 0;
     let mut n_read = 0;
     let mut n_write = 0;
+    let mut n_read_total = 0;
+    let mut n_write_total = 0;
+
+    let params = report::RunParams {
+        engine: engine.to_string(),
+        n_threads,
+        virtual_users: if engine == Engine::Async { Some(virtual_users) } else { None },
+        batch_size,
+        read_only,
+        n_users: n,
+        n_workout_templates: workout_templates.len(),
+        connect: http.connect.to_string(),
+        connection_mode: http.connection_mode.to_string(),
+        pool_size: http.pool_size,
+        load_profile: load.profile().map(|x| x.to_string()),
+        job_mix: mix.clone(),
+    };
+
+    let mut schedule = load.profile().map(|profile| {
+        match profile.total_duration() {
+            Some(d) => println!("open-loop mode: load profile {} (runs for {})", profile, humantime::format_duration(d)),
+            None => println!("open-loop mode: load profile {}", profile),
+        }
+        Schedule::new(profile, load.max_schedule_lag)
+    });
+    let mut batch: Vec<usize> = Vec::with_capacity(batch_size);
+    let mut n_requests = 0;
+    let mut interval_latencies = Latencies::default();
+    let mut total_latencies = Latencies::default();
+    let mut intervals: Vec<IntervalStats> = Vec::new();
+    let mut slo_monitor = SloMonitor::new(&slo);
+    let mut error_rate = budget.max_error_rate.map(|max| ErrorRate::new(budget.error_rate_window, max));
+    // failure rate over --error-rate-window, set on display ticks where it's above --max-error-rate
+    let mut error_rate_exceeded: Option<f64> = None;
+    let mut dashboard = match tui {
+        true => Some(Dashboard::new(schedule.is_some()).expect("failed to start --tui dashboard")),
+        false => None,
+    };
+    let mut paused = false;
+    let mut last_input = Instant::now();
+    let run_start = Instant::now();
+    let mut resource_monitor = ResourceMonitor::new(&resources, run_start);
+
+    let stop_reason = loop {
+        if let Some(dashboard) = dashboard.as_mut() {
+            if last_input.elapsed() >= Duration::from_millis(50) {
+                last_input = Instant::now();
+                match dashboard.poll_input() {
+                    Some(Command::TogglePause) => {
+                        paused = ! paused;
+                        if let Some(schedule) = schedule.as_mut() {
+                            if paused { schedule.pause() } else { schedule.resume() }
+                        }
+                        dashboard::notice(format!("job dispatch {} at {:.0}s",
+                            if paused { "paused" } else { "resumed" },
+                            run_start.elapsed().as_secs_f64(),
+                        ));
+                        dashboard.set_paused(paused);
+                    }
+
+                    Some(Command::ScaleRate(factor)) => match schedule.as_mut() {
+                        Some(schedule) => {
+                            schedule.scale_rate(factor);
+                            dashboard::notice(format!("rate changed to x{:.2} of the load profile at {:.0}s",
+                                schedule.rate_scale(),
+                                run_start.elapsed().as_secs_f64(),
+                            ));
+                        }
+                        None => dashboard::notice("changing the rate requires --rate or --profile".to_string()),
+                    }
+
+                    Some(Command::Stop) => term.store(true, Ordering::Relaxed),
+
+                    None => {}
+                }
+            }
+        }
+
+        if batch.is_empty() {
+            batch.extend(ix.choose_multiple_weighted(&mut rng, batch_size, |&i| { user_engagement_scores[i] }).unwrap());
+        }
+
+        // in --rate mode, wait for the next slot on the timeline before generating the job, so
+        // that no user state is changed for a job that ends up being dropped
+        let slot = if paused {
+            // jobs generated before pausing are still handed out
+            if let Some(schedule) = schedule.as_mut() { schedule.flush(&txs); }
+            std::thread::sleep(Duration::from_millis(10));
+            None
+        } else {
+            match schedule.as_mut() {
+                Some(schedule) => match schedule.next_slot(&txs) {
+                    Slot::Send(t) => Some(Some(t)),
+                    Slot::Dropped | Slot::Idle => None,
+                    Slot::Done => break StopReason::ProfileComplete,
+                }
+                None => Some(None),
+            }
+        };
 
-    loop {
-        let loop_time = Instant::now();
-        for &i in ix.choose_multiple_weighted(&mut rng, batch_size, |&i| { user_engagement_scores[i] }).unwrap() {
+        if let Some(scheduled) = slot {
+            let i = batch.pop().unwrap();
             let state = &mut user_states[i];
-            let is_write = ! read_only && state.pos < state.workouts.len() && uniform.sample(&mut rng) > 0.80;
+            let is_write = ! read_only && state.pos < state.workouts.len() && uniform.sample(&mut rng) >= mix.read_probability;
             let job = match is_write {
                 true => {
                     n_write += 1;
-                    let workouts: Vec<fitbod::Workout> = state.workouts.iter()
-                        .enumerate()
-                        .skip(state.pos.saturating_sub(10))
-                        .take(15)
-                        .map(|(i, w)| {
-                            let mut w = w.clone();
-                            w.user_id = state.user_id;
-                            w.workout_id = state.workout_ids[i];
-                            w
-                        }).collect();
-                    let n_new = {
-                        let read_lock = state.inserted.read().unwrap();
-                        workouts.iter().filter(|x| ! read_lock.contains(&x.workout_id)).count()
-                    };
-                    state.pos += n_new;
+                    n_write_total += 1;
+                    let (workouts, n_new) = state.next_write(mix.write_size, mix.write_resend);
                     n_pending_inserts += n_new;
                     StressTestJob::Write {
                         user_id: state.user_id,
                         workouts,
                         key: state.key.clone(),
                         inserted: state.inserted.clone(),
+                        scheduled,
                     }
                 }
 
                 false => {
                     n_read += 1;
+                    n_read_total += 1;
+                    let window = match uniform.sample(&mut rng) < mix.window_probability {
+                        true => ListWindow::random(&mut rng, state.written(), mix.list_limit),
+                        false => ListWindow { limit: mix.list_limit, ..Default::default() },
+                    };
                     StressTestJob::Read {
                         user_id: state.user_id,
+                        window,
                         key: state.key.clone(),
                         inserted: state.inserted.clone(),
+                        scheduled,
                     }
                 }
             };
-            // send to first available thread - each has small queue size so can fill up
-            let mut job = Some(job);
-            while job.is_some() {
-                job = {
-                    let job = job.take().unwrap();
-                    match txs[next_thread % n_threads].try_send(job) {
-                        Ok(_) => None,
-                        Err(crossbeam_channel::TrySendError::Full(j)) => Some(j),
-                        Err(crossbeam_channel::TrySendError::Disconnected(_)) => panic!("thread rx disconnected"),
+            match schedule.as_mut() {
+                Some(schedule) => schedule.push(job, &txs),
+
+                None => {
+                    // send to first available worker - each has small queue size so can fill up
+                    let mut job = Some(job);
+                    while job.is_some() {
+                        job = txs[next_thread % n_workers].try_send(job.take().unwrap());
+                        next_thread += 1;
                     }
-                };
-                next_thread += 1;
+                }
             }
             n_jobs_sent += 1;
+            n_requests += 1;
         }
 
         let loop_end = Instant::now();
         if loop_end.saturating_duration_since(last_disp) > Duration::from_secs(1) {
             let elapsed = loop_end.saturating_duration_since(last_disp);
-            println!("{} jobs ({} read / {} write) in last {:?} - {} inserted (incl pending) vs. {} inserted (confirmed)",
-                n_jobs_sent.thousands_sep(),
-                n_read.thousands_sep(),
-                n_write.thousands_sep(),
-                elapsed,
-                n_pending_inserts.thousands_sep(),
-                n_inserted.load(Ordering::Relaxed).thousands_sep(),
-            );
+            let cpu = process_cpu_time();
+            let cpu_pct = (cpu - last_cpu).as_secs_f64() / elapsed.as_secs_f64() * 100.0;
+            metrics.harness_cpu(cpu_pct.round() as i64);
+            let schedule_info = match schedule.as_ref() {
+                Some(schedule) => {
+                    metrics.schedule(schedule.backlog(), schedule.n_dropped, schedule.lag(), schedule.target_rate());
+                    format!(" - target {:.0} req/s ({}) - backlog {} ({:?} behind schedule) - {} dropped",
+                        schedule.target_rate(),
+                        schedule.current_stage().map(|x| x.to_string()).unwrap_or_default(),
+                        schedule.backlog().thousands_sep(),
+                        schedule.lag(),
+                        schedule.n_dropped.thousands_sep(),
+                    )
+                }
+                None => String::new(),
+            };
+            interval_latencies.drain_from(&latency_shards);
+            let server = resource_monitor.sample_process();
+            let db = resource_monitor.sample_db();
+            if let Some(sample) = server.as_ref() { metrics.server_process(sample); }
+            if let Some(sample) = db.as_ref() { metrics.server_db(sample); }
+            match dashboard.as_mut() {
+                Some(dashboard) => {
+                    dashboard.update(dashboard::Tick {
+                        elapsed: loop_end.saturating_duration_since(run_start),
+                        interval: elapsed,
+                        latencies: &interval_latencies,
+                        snapshot: counters.snapshot(n_read_total, n_write_total, n_pending_inserts),
+                        harness_cpu_pct: cpu_pct,
+                        queue_depths: txs.iter().map(|tx| tx.queue_len()).collect(),
+                        queue_capacity: txs[0].queue_capacity(),
+                        schedule: schedule.as_ref(),
+                        server: server.as_ref(),
+                        db: db.as_ref(),
+                    });
+                }
+
+                None => {
+                    println!("{} jobs ({} read / {} write) in last {:?} - {} inserted (incl pending) vs. {} inserted (confirmed) - {} errors - {} timeouts - {} malformed responses - harness cpu {:.0}%{}",
+                        n_jobs_sent.thousands_sep(),
+                        n_read.thousands_sep(),
+                        n_write.thousands_sep(),
+                        elapsed,
+                        n_pending_inserts.thousands_sep(),
+                        counters.n_inserted.load(Ordering::Relaxed).thousands_sep(),
+                        counters.n_errors.load(Ordering::Relaxed).thousands_sep(),
+                        counters.n_timeouts.load(Ordering::Relaxed).thousands_sep(),
+                        counters.n_malformed.load(Ordering::Relaxed).thousands_sep(),
+                        cpu_pct,
+                        schedule_info,
+                    );
+                    interval_latencies.print_summary();
+                    if let Some(sample) = server.as_ref() { println!("    {}", sample.fmt_line()); }
+                    if let Some(sample) = db.as_ref() { println!("    {}", sample.fmt_line()); }
+                }
+            }
+            slo_monitor.observe(loop_end.saturating_duration_since(run_start), elapsed, &interval_latencies);
+            if report_path.is_some() {
+                intervals.push(IntervalStats {
+                    elapsed_secs: loop_end.saturating_duration_since(run_start).as_secs_f64(),
+                    duration_secs: elapsed.as_secs_f64(),
+                    n_jobs: n_jobs_sent,
+                    n_read,
+                    n_write,
+                    workouts_confirmed: counters.n_inserted.load(Ordering::Relaxed),
+                    n_timeouts: counters.n_timeouts.load(Ordering::Relaxed),
+                    harness_cpu_pct: cpu_pct,
+                    endpoints: EndpointStats::from_latencies(&interval_latencies),
+                    server,
+                    db,
+                });
+            }
+            total_latencies.merge(&interval_latencies);
+            interval_latencies.clear();
+            metrics.run_snapshot(&counters.snapshot(n_read_total, n_write_total, n_pending_inserts));
+            metrics.flush();
+            error_rate_exceeded = error_rate.as_mut()
+                .and_then(|x| x.exceeded(loop_end, n_requests, counters.n_errors.load(Ordering::Relaxed)));
             last_disp = loop_end;
+            last_cpu = cpu;
             n_jobs_sent = 0;
             n_read = 0;
             n_write = 0;
         }
 
-        if term.load(Ordering::Relaxed) { break }
+        if let Some(max) = budget.max_timeouts {
+            let n = counters.n_timeouts.load(Ordering::Relaxed);
+            if n > max {
+                dashboard::notice(format!("aborting: {} timeouts exceeds --max-timeouts {}", n.thousands_sep(), max.thousands_sep()));
+                break StopReason::MaxTimeouts
+            }
+        }
+
+        if let Some(max) = budget.max_errors {
+            let n = counters.n_errors.load(Ordering::Relaxed);
+            if n > max {
+                dashboard::notice(format!("aborting: {} failures exceeds --max-errors {}", n.thousands_sep(), max.thousands_sep()));
+                break StopReason::MaxErrors
+            }
+        }
+
+        if let Some(rate) = error_rate_exceeded.take() {
+            dashboard::notice(format!("aborting: {:.2}% of requests failed over the last {} (--max-error-rate {}%)",
+                rate, humantime::format_duration(budget.error_rate_window), budget.max_error_rate.unwrap()));
+            break StopReason::MaxErrorRate
+        }
+
+        if let Some(max) = stop.duration {
+            if loop_end.saturating_duration_since(run_start) >= max { break StopReason::Duration }
+        }
+
+        if let Some(max) = stop.max_requests {
+            if n_requests >= max { break StopReason::MaxRequests }
+        }
+
+        if let Some(max) = stop.max_workouts_inserted {
+            if counters.n_inserted.load(Ordering::Relaxed) >= max { break StopReason::MaxWorkoutsInserted }
+        }
+
+        if term.load(Ordering::Relaxed) { break StopReason::Signal }
+    };
+    if let Some(dashboard) = dashboard.take() {
+        dashboard.close();
+    }
+    println!("stopping: {} (after {} requests in {:?})",
+        stop_reason,
+        n_requests.thousands_sep(),
+        Instant::now().saturating_duration_since(run_start),
+    );
+
+    if let Some(schedule) = schedule.as_mut() {
+        // backlogged write jobs have already been counted as pending inserts
+        schedule.drain(&txs);
     }
-    println!("exit signal received");
 
     for tx in txs.iter() {
-        tx.send(StressTestJob::Exit).unwrap();
+        tx.send(StressTestJob::Exit);
     }
 
     for join_handle in threads {
         let _ = join_handle.join().unwrap();
     }
+    if let Some(rt) = rt {
+        rt.block_on(async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+    }
     println!("joined threads");
 
-    if ! read_only {
-        let failed_verifications: Vec<Uuid> = user_states.par_iter().filter(|x| x.pos > 0).map_init(
-            || {
-                let mut tera = tera::Tera::default();
-                tera.add_raw_template("api-request", API_REQUEST).unwrap();
-                tera
-            },
+    total_latencies.drain_from(&latency_shards);
+    let run_duration = Instant::now().saturating_duration_since(run_start);
+    println!("latency percentiles (whole run):");
+    total_latencies.print_table();
+    let failures = counters.failure_counts();
+    if ! failures.is_empty() {
+        println!("failures ({} total):", counters.n_errors.load(Ordering::Relaxed).thousands_sep());
+        for report::FailureCount { endpoint, class, count } in failures.iter() {
+            println!("    {:<24} {:<40} {:>10}", endpoint, class, count.thousands_sep());
+        }
+    }
+    let slo_results = slo_monitor.finish(&total_latencies, run_duration);
+    if let Some(peak) = resource_monitor.summary().peak_rss_bytes {
+        println!("api server peak rss {} ({} workouts confirmed)",
+            resources::fmt_bytes(peak as i64),
+            counters.n_inserted.load(Ordering::Relaxed).thousands_sep(),
+        );
+    }
+    if let Some(sample) = resource_monitor.summary().last_db_sample.as_ref() {
+        println!("last {} (at {:.0}s)", sample.fmt_line(), sample.elapsed_secs);
+    }
 
-            |tera, UserState { user_id, inserted, key, .. }| {
-                let req = fitbod::api::ListWorkoutsRequest::from(*user_id);
-                let resp = api_request(&addr, "/api/v1/workouts/list", &req, &key, tera, &influx).unwrap();
-                let resp: fitbod::api::ListWorkoutsResponse = serde_json::from_slice(&resp[..]).unwrap();
-                let resp_wids: HashSet<Uuid> = resp.items.iter().map(|x| x.workout_id).collect();
-                let expected_wids = inserted.read().unwrap();
-                if expected_wids.symmetric_difference(&resp_wids).count() > 0 {
-                    Some(*user_id)
-                } else {
-                    None
-                }
+    // the final check isn't recorded, so the history is complete once the workers are done
+    let history_violations = history.map(|history| {
+        history.flush();
+        let ops = history::load(history_path.as_deref().unwrap());
+        let violations = history::check(&ops);
+        history::print_check(&ops, &violations, 20);
+        violations.len()
+    });
+
+    let mut db_verification = None;
+    let verification = if stop_reason.is_abort() {
+        println!("skipping final check (run aborted) - {} failures ({} timeouts) in {:?}",
+            counters.n_errors.load(Ordering::Relaxed).thousands_sep(),
+            counters.n_timeouts.load(Ordering::Relaxed).thousands_sep(),
+            Instant::now().saturating_duration_since(begin),
+        );
+        None
+    } else if ! read_only {
+        let checked: Vec<&UserState> = user_states.iter().filter(|x| x.pos > 0).collect();
+        let stored = db_url.as_ref().map(|db_url| {
+            let user_ids: Vec<Uuid> = checked.iter().map(|x| x.user_id).collect();
+            dbcheck::load_workouts(db_url, &user_ids)
+        });
+        let results: Vec<(bool, Option<report::DbUserCheck>)> = checked.par_iter().map_init(
+            || ApiClient::new(&http, metrics.clone(), Default::default()),
+
+            |client, UserState { user_id, inserted, key, .. }| {
+                let window = ListWindow::default();
+                let resp = client.request("/api/v1/workouts/list", &window.request(*user_id), key)
+                    .and_then(|body| http::decode::<fitbod::api::ListWorkoutsResponse>(&body[..]));
+                let mut expected = inserted.blocking_write();
+                let (failed, items) = match resp {
+                    Ok(resp) => match expected.verify(*user_id, &resp.items[..], &window) {
+                        Ok(_) => (false, Some(resp.items)),
+                        Err(mismatches) => {
+                            eprintln!("final check failed for user id {} ({} mismatches):\n{}", user_id, mismatches.len(), oracle::fmt_diff(&mismatches));
+                            (true, Some(resp.items))
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("final check request failed for user id {}: {}", user_id, e);
+                        (true, None)
+                    }
+                };
+
+                let db_check = stored.as_ref().map(|stored| {
+                    let rows = &stored[user_id];
+                    let mismatches = expected.verify_stored(*user_id, rows);
+                    if ! mismatches.is_empty() {
+                        eprintln!("db check failed for user id {} ({} mismatches):\n{}", user_id, mismatches.len(), oracle::fmt_diff(&mismatches));
+                    }
+                    let (n_missing, n_extra, n_mutated) = dbcheck::count_rows(&mismatches);
+                    // compared to the stored rows, so that it's clear which side is wrong
+                    // when the api check passes but the db check doesn't (or vice versa)
+                    let diverged = match items.as_ref() {
+                        Some(items) => {
+                            let mismatches = dbcheck::diff_served(*user_id, rows, items);
+                            if ! mismatches.is_empty() {
+                                eprintln!("api response differs from db rows for user id {} ({} mismatches):\n{}", user_id, mismatches.len(), oracle::fmt_diff(&mismatches));
+                            }
+                            ! mismatches.is_empty()
+                        }
+                        None => false,
+                    };
+                    report::DbUserCheck { user_id: *user_id, n_rows: rows.len(), n_missing, n_extra, n_mutated, diverged }
+                });
+                (failed, db_check)
             }
-        ).filter_map(|x| x).collect();
+        ).collect();
+        let failed_verifications: Vec<Uuid> = checked.iter().zip(results.iter())
+            .filter(|(_, (failed, _))| *failed)
+            .map(|(x, _)| x.user_id)
+            .collect();
         println!("finished checking user list results");
 
+        if stored.is_some() {
+            let db = report::DbVerification::new(results.into_iter().filter_map(|(_, x)| x));
+            println!("{}", db.fmt_line());
+            db_verification = Some(db);
+        }
+
         if ! failed_verifications.is_empty() {
             dbg!(&failed_verifications);
-            panic!("final check failed for {} users", failed_verifications.len());
+            println!("final check failed for {} users", failed_verifications.len());
+            counters.n_verification_failures.fetch_add(failed_verifications.len(), Ordering::Relaxed);
         }
+        Some(report::Verification { n_users_checked: checked.len(), failed_user_ids: failed_verifications })
+    } else {
+        None
+    };
+
+    if let Some(path) = report_path.as_deref() {
+        let report = RunReport {
+            params,
+            env: report::EnvInfo::collect(),
+            start: begin_utc,
+            end: Utc::now(),
+            duration_secs: run_duration.as_secs_f64(),
+            stop_reason: stop_reason.to_string(),
+            n_requests,
+            endpoints: EndpointStats::from_latencies(&total_latencies),
+            workouts_pending: n_pending_inserts,
+            workouts_confirmed: counters.n_inserted.load(Ordering::Relaxed),
+            n_timeouts: counters.n_timeouts.load(Ordering::Relaxed),
+            n_malformed: counters.n_malformed.load(Ordering::Relaxed),
+            n_errors: counters.n_errors.load(Ordering::Relaxed),
+            failures,
+            n_verification_failures: counters.n_verification_failures.load(Ordering::Relaxed),
+            verification: verification.clone(),
+            history_violations,
+            db_verification: db_verification.clone(),
+            intervals,
+            slos: slo_results.clone(),
+            resources: match resource_monitor.is_enabled() {
+                true => Some(resource_monitor.summary().clone()),
+                false => None,
+            },
+        };
+        report.save(path);
+        println!("wrote run report to {}", path.display());
+    }
+
+    metrics.run_snapshot(&counters.snapshot(n_read_total, n_write_total, n_pending_inserts));
+    metrics.flush();
+
+    if stop_reason.is_abort() {
+        std::process::exit(EXIT_ABORTED);
+    }
+    let n_verification_failures = counters.n_verification_failures.load(Ordering::Relaxed);
+    if n_verification_failures > 0 {
+        println!("{} read checks failed", n_verification_failures.thousands_sep());
+        std::process::exit(EXIT_VERIFICATION_FAILED);
     }
-    println!("all done in {:?}", Instant::now().saturating_duration_since(begin));
+    if history_violations.unwrap_or(0) > 0 {
+        std::process::exit(EXIT_VERIFICATION_FAILED);
+    }
+    if db_verification.map(|x| x.is_failed()).unwrap_or(false) {
+        std::process::exit(EXIT_VERIFICATION_FAILED);
+    }
+    if slo_results.iter().any(|x| ! x.passed()) {
+        println!("slo(s) not met");
+        std::process::exit(EXIT_SLO_VIOLATED);
+    }
+    println!("all done in {:?} (harness cpu time {:?})", Instant::now().saturating_duration_since(begin), process_cpu_time());
 }
 
 fn insert_workouts_test(
     workouts_csv_path: &Path,
     users_csv_path: &Path,
     n_threads: usize,
-    addr: SocketAddr,
+    http: HttpOpts,
+    metrics: MetricsOpts,
 ) {
     let mut keys = load_private_keys(users_csv_path);
     let email_uid: HashMap<String, Uuid> = keys.iter().map(|x| (x.email.clone(), x.user_id)).collect();
@@ -896,7 +1778,7 @@ fn insert_workouts_test(
         workouts.push(fitbod::Workout { user_id, workout_id: Uuid::new_v4(), start_time, end_time });
     }
 
-    let uid_wid: HashMap<Uuid, Arc<Mutex<HashSet<Uuid>>>> = email_uid.values()
+    let uid_wid: HashMap<Uuid, Arc<Mutex<HashMap<Uuid, fitbod::Workout>>>> = email_uid.values()
         .map(|&uid| (uid, Default::default()))
         .collect();
     let uid_wid = Arc::new(uid_wid);
@@ -906,16 +1788,14 @@ fn insert_workouts_test(
         thread_jobs[i % n_threads].push(workout);
     }
 
-    let influx = InfluxWriter::new("localhost", "fitbod");
+    let metrics = MetricsSink::new(&metrics);
 
     let threads: Vec<std::thread::JoinHandle<()>> = (0..n_threads).map(|i| {
         let mut jobs = Vec::new();
         std::mem::swap(&mut jobs, &mut thread_jobs[i]);
         let uid_wid = Arc::clone(&uid_wid);
         let uid_key = uid_key.clone();
-        let mut tera = tera::Tera::default();
-        tera.add_raw_template("api-request", API_REQUEST).unwrap();
-        let influx = influx.clone();
+        let mut client = ApiClient::new(&http, metrics.clone(), Default::default());
         std::thread::spawn(move || {
             while let Some(workout) = jobs.pop() {
                 let user_id = workout.user_id;
@@ -927,16 +1807,16 @@ fn insert_workouts_test(
 
                 let mut write_lock = uid_wid[&workout.user_id].lock().unwrap();
 
-                let _resp = api_request(&addr, "/api/v1/workouts/new", &req, key, &mut tera, &influx).unwrap();
+                let _resp = client.request("/api/v1/workouts/new", &req, key).unwrap();
 
-                assert!( write_lock.insert(workout.workout_id) ); // assert! is verifying that workout_id did not exist in set
+                assert!( write_lock.insert(workout.workout_id, workout.clone()).is_none() ); // assert! is verifying that workout_id did not exist in map
 
-                let local_copy: HashSet<_> = (&*write_lock).clone();
+                let expected = oracle::expected(write_lock.values(), &ListWindow::default());
 
                 // now check results of /api/v1/workouts/list
 
                 let req = fitbod::api::ListWorkoutsRequest::from(user_id);
-                let resp = api_request(&addr, "/api/v1/workouts/list", &req, key, &mut tera, &influx).unwrap();
+                let resp = client.request("/api/v1/workouts/list", &req, key).unwrap();
 
                 drop(write_lock);
 
@@ -947,10 +1827,11 @@ fn insert_workouts_test(
                     );
                     e
                 }).unwrap();
-                let resp_wids: HashSet<Uuid> = resp.items.iter().map(|x| x.workout_id).collect();
+                let mismatches = oracle::diff(user_id, &expected, &resp.items);
 
-                assert_eq!( resp_wids.symmetric_difference(&local_copy).count(), 0,
-                    "ERROR!\n\nresp:\n{:#?}\\nnwrite_lock:\n{:#?}\n\nworkout:\n{:#?}", resp, local_copy, workout,
+                assert!( mismatches.is_empty(),
+                    "ERROR! {} mismatches in list response after inserting workout {} for user id {}:\n{}",
+                    mismatches.len(), workout.workout_id, user_id, oracle::fmt_diff(&mismatches),
                 );
             }
         })
@@ -959,10 +1840,16 @@ fn insert_workouts_test(
     for join_handle in threads {
         let res = join_handle.join().unwrap();
     }
+    metrics.flush();
 }
 
-fn would_block(err: &io::Error) -> bool {
-    err.kind() == io::ErrorKind::WouldBlock
+/// cpu time (user + system, all threads) consumed by this process so far
+fn process_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    assert_eq!(ret, 0, "getrusage failed: {}", std::io::Error::last_os_error());
+    let tv = |t: libc::timeval| Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64);
+    tv(usage.ru_utime) + tv(usage.ru_stime)
 }
 
 fn setup_random_users(output_path: &Path, n_users: usize, chunk_size: usize) {
@@ -1027,7 +1914,11 @@ fn setup_random_users(output_path: &Path, n_users: usize, chunk_size: usize) {
 
 fn main() {
     dotenv::dotenv().unwrap();
-    match Opt::from_args() {
+    let opt = Opt::from_args();
+    if let Opt::StressTest { mix, .. } = &opt {
+        if let Err(e) = mix.validate() { e.exit() }
+    }
+    match opt {
         Opt::SetupExampleUsers { input_path, output_path } => {
             assert!(input_path.exists(), "path does not exist: {}", input_path.display());
             setup_example_users(&input_path, &output_path);
@@ -1038,10 +1929,10 @@ fn main() {
             load_example_users_to_db(&users_csv_path, truncate_users, vacuum_full_analyze);
         }
 
-        Opt::InsertWorkoutsTest { workouts_csv_path, users_csv_path, n_threads, connect } => {
+        Opt::InsertWorkoutsTest { workouts_csv_path, users_csv_path, n_threads, http, metrics } => {
             assert!(workouts_csv_path.exists(), "path does not exist: {}", workouts_csv_path.display());
             assert!(users_csv_path.exists(), "path does not exist: {}", users_csv_path.display());
-            insert_workouts_test(&workouts_csv_path, &users_csv_path, n_threads, connect);
+            insert_workouts_test(&workouts_csv_path, &users_csv_path, n_threads, http, metrics);
         }
 
         Opt::ListWorkoutsRequest { users_csv_path, user_id, start, end, limit, email, curl } => {
@@ -1059,13 +1950,160 @@ fn main() {
         }
 
         Opt::StressTest {
-            workouts_csv_path, users_csv_path, n_threads, connect,
-            batch_size, read_only,
+            workouts_csv_path, users_csv_path, n_threads, engine, virtual_users, http, load,
+            metrics, stop, slo, resources, report_path, batch_size, mix, read_only, budget, history_path,
+            unlocked, verify_db, tui,
         } => {
-            stress_test(
-                &workouts_csv_path, &users_csv_path, n_threads, batch_size,
-                connect, read_only,
-            );
+            stress_test(StressTestConfig {
+                workouts_csv_path, users_csv_path, n_threads, engine, virtual_users, batch_size, mix,
+                http, load, metrics, stop, slo, resources, report_path, read_only, budget,
+                history_path, unlocked, verify_db, tui,
+            });
+        }
+
+        Opt::CompareRuns { baseline, candidate, thresholds } => {
+            let baseline = RunReport::load(&baseline);
+            let candidate = RunReport::load(&candidate);
+            if compare::compare_runs(&baseline, &candidate, &thresholds) {
+                std::process::exit(EXIT_REGRESSION);
+            }
+        }
+
+        Opt::AuthTest { users_csv_path, timestamp_skew, http, metrics } => {
+            assert!(users_csv_path.exists(), "path does not exist: {}", users_csv_path.display());
+            if ! authtest::auth_test(&users_csv_path, http, metrics, timestamp_skew) {
+                std::process::exit(EXIT_AUTH_FAILED);
+            }
         }
+
+        Opt::CheckHistory { history_path, max_shown } => {
+            let ops = history::load(&history_path);
+            let violations = history::check(&ops);
+            if history::print_check(&ops, &violations, max_shown) {
+                std::process::exit(EXIT_VERIFICATION_FAILED);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_state(n_workouts: usize) -> UserState {
+        let t0 = Utc.ymd(2021, 1, 1).and_hms(6, 30, 0);
+        let workouts = (0..n_workouts).map(|i| {
+            let start_time = t0 + chrono::Duration::days(i as i64);
+            fitbod::Workout { user_id: Uuid::nil(), workout_id: Uuid::nil(), start_time, end_time: start_time + chrono::Duration::minutes(45) }
+        }).collect();
+        UserState {
+            user_id: Uuid::new_v4(),
+            key: [0; 64],
+            workouts: Arc::new(workouts),
+            workout_ids: (0..n_workouts).map(|_| Uuid::new_v4()).collect(),
+            inserted: Default::default(),
+            pos: 0,
+        }
+    }
+
+    #[test]
+    fn queued_writes_dont_count_the_same_workouts_twice() {
+        let mut state = user_state(20);
+        // none of these are confirmed (in `inserted`) before the next is issued
+        let (workouts, n_new) = state.next_write(15, 10);
+        assert_eq!((workouts.len(), n_new, state.pos), (15, 15, 15));
+        let (workouts, n_new) = state.next_write(15, 10);
+        assert_eq!((workouts.len(), n_new, state.pos), (15, 5, 20));
+        assert_eq!(workouts[0].workout_id, state.workout_ids[5]);
+        let (workouts, n_new) = state.next_write(15, 10);
+        assert_eq!((workouts.len(), n_new, state.pos), (10, 0, 20));
+
+        assert_eq!(state.written().len(), 20);
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            ListWindow::random(&mut rng, state.written(), None);
+        }
+    }
+
+    #[test]
+    fn write_sets_user_and_workout_ids() {
+        let mut state = user_state(3);
+        let (workouts, n_new) = state.next_write(15, 10);
+        assert_eq!(n_new, 3);
+        for (i, w) in workouts.iter().enumerate() {
+            assert_eq!(w.user_id, state.user_id);
+            assert_eq!(w.workout_id, state.workout_ids[i]);
+        }
+    }
+
+    #[test]
+    fn worker_counts_must_be_positive() {
+        for flag in &["--virtual-users", "--n-threads", "--batch-size"] {
+            assert!(Opt::from_iter_safe(&["fitbod-test", "stress-test", flag, "0"]).is_err(), "{} 0 accepted", flag);
+            assert!(Opt::from_iter_safe(&["fitbod-test", "stress-test", flag, "8"]).is_ok(), "{} 8 rejected", flag);
+        }
+        assert!(Opt::from_iter_safe(&["fitbod-test", "insert-workouts-test", "-j", "0"]).is_err());
+    }
+
+    #[test]
+    fn job_mix_is_validated() {
+        let mix = |args: &[&str]| JobMixOpts::from_iter_safe(std::iter::once("test").chain(args.iter().cloned()));
+        for flag in &["--read-probability", "--window-probability"] {
+            for bad in &["1.01", "1.5", "half"] {
+                assert!(mix(&[flag, bad]).is_err(), "{} {} accepted", flag, bad);
+            }
+            for ok in &["0", "0.5", "1"] {
+                assert!(mix(&[flag, ok]).is_ok(), "{} {} rejected", flag, ok);
+            }
+        }
+        assert!(mix(&[]).unwrap().validate().is_ok());
+        assert!(mix(&["--write-size", "5", "--write-resend", "4"]).unwrap().validate().is_ok());
+        let e = mix(&["--write-size", "5", "--write-resend", "5"]).unwrap().validate().unwrap_err();
+        assert_eq!(e.kind, structopt::clap::ErrorKind::ValueValidation);
+    }
+
+    /// (time, requests, failures) samples fed to `ErrorRate::update`, returning each result.
+    /// times are seconds after an arbitrary start.
+    fn error_rates(window: u64, samples: &[(u64, usize, usize)]) -> Vec<Option<f64>> {
+        let t0 = Instant::now();
+        let mut x = ErrorRate::new(Duration::from_secs(window), 10.0);
+        samples.iter().map(|&(t, n, n_err)| x.update(t0 + Duration::from_secs(t), n, n_err)).collect()
+    }
+
+    #[test]
+    fn error_rate_waits_for_a_full_window() {
+        assert_eq!(error_rates(60, &[(0, 0, 0), (30, 100, 50), (59, 200, 100), (60, 300, 100)]),
+            vec![None, None, None, Some(100.0 / 300.0 * 100.0)]);
+    }
+
+    #[test]
+    fn error_rate_window_rolls_over() {
+        let rates = error_rates(60, &[(0, 0, 0), (30, 100, 50), (60, 200, 50), (90, 300, 50), (120, 400, 150), (150, 500, 150)]);
+        // each rate is over the 60s before the sample (using the newest sample at or before
+        // the start of the window as the baseline)
+        assert_eq!(rates, vec![None, None, Some(25.0), Some(0.0), Some(50.0), Some(50.0)]);
+    }
+
+    #[test]
+    fn error_rate_baseline_may_be_older_than_the_window() {
+        // samples can be further apart than the window; the rate is then over a longer period
+        assert_eq!(error_rates(10, &[(0, 0, 0), (30, 100, 20), (35, 150, 20)]), vec![None, Some(20.0), Some(20.0 / 150.0 * 100.0)]);
+    }
+
+    #[test]
+    fn error_rate_needs_requests_in_the_window() {
+        assert_eq!(error_rates(10, &[(0, 100, 10), (10, 100, 10), (20, 101, 11)]), vec![None, None, Some(100.0)]);
+    }
+
+    #[test]
+    fn error_rate_threshold_is_exclusive() {
+        let t0 = Instant::now();
+        let t = |secs: u64| t0 + Duration::from_secs(secs);
+        let mut x = ErrorRate::new(Duration::from_secs(10), 10.0);
+        assert_eq!(x.exceeded(t(0), 0, 0), None);
+        // exactly 10% isn't over the max
+        assert_eq!(x.exceeded(t(10), 100, 10), None);
+        assert_eq!(x.exceeded(t(20), 200, 21), Some(11.0));
+        assert_eq!(x.exceeded(t(30), 300, 21), None);
     }
 }
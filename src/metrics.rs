@@ -0,0 +1,408 @@
+//! where stress-test and insert-workouts-test send their metrics
+//!
+//! every measurement goes through a typed method on `MetricsSink`, which forwards it to the
+//! configured backend: influxdb, a local line-protocol file, a prometheus scrape endpoint,
+//! or nowhere. the prometheus exporter can also be enabled alongside another backend
+//! (--serve-metrics).
+
+use std::time::*;
+use std::io::{BufWriter, prelude::*};
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::fmt;
+use chrono::prelude::*;
+use structopt::StructOpt;
+use influx_writer::{InfluxWriter, measure};
+
+use crate::prometheus::Registry;
+use crate::resources::{DbSample, ProcessSample};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkKind {
+    Influx,
+    File,
+    Prometheus,
+    None,
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "influx" => Ok(SinkKind::Influx),
+            "file" => Ok(SinkKind::File),
+            "prometheus" => Ok(SinkKind::Prometheus),
+            "none" => Ok(SinkKind::None),
+            other => Err(format!("invalid metrics sink '{}' (expected influx, file, prometheus or none)", other)),
+        }
+    }
+}
+
+impl fmt::Display for SinkKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SinkKind::Influx => "influx",
+            SinkKind::File => "file",
+            SinkKind::Prometheus => "prometheus",
+            SinkKind::None => "none",
+        })
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct MetricsOpts {
+    /// where to send metrics: influx, file (influx line protocol written to --metrics-file),
+    /// prometheus (served at --metrics-listen) or none
+    #[structopt(long, env = "FITBOD_METRICS_SINK", default_value = "influx")]
+    pub metrics_sink: SinkKind,
+
+    #[structopt(long, env = "INFLUX_HOST", default_value = "localhost")]
+    pub influx_host: String,
+
+    #[structopt(long, env = "INFLUX_DB", default_value = "fitbod")]
+    pub influx_db: String,
+
+    /// added as a `run` tag (or prometheus label) to every measurement, to tell runs apart
+    /// when sharing a metrics host. without it, influx measurements are tagged `run=none`.
+    #[structopt(long, env = "FITBOD_METRICS_RUN_TAG")]
+    pub run_tag: Option<String>,
+
+    #[structopt(long, env = "FITBOD_METRICS_FILE", default_value = "var/metrics.lp")]
+    pub metrics_file: PathBuf,
+
+    /// address the prometheus exporter listens on (serves /metrics)
+    #[structopt(long, env = "FITBOD_METRICS_LISTEN", default_value = "127.0.0.1:9898")]
+    pub metrics_listen: SocketAddr,
+
+    /// serve prometheus /metrics at --metrics-listen in addition to sending metrics to
+    /// --metrics-sink (implied by --metrics-sink prometheus)
+    #[structopt(long)]
+    pub serve_metrics: bool,
+}
+
+/// state of a stress-test run, as printed by the manager loop once per second
+pub struct RunSnapshot {
+    pub n_read: usize,
+    pub n_write: usize,
+    pub workouts_pending: usize,
+    pub workouts_confirmed: usize,
+    pub n_timeouts: usize,
+    pub n_malformed: usize,
+    pub n_errors: usize,
+    pub n_verification_failures: usize,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Influx(InfluxWriter),
+    File(Arc<Mutex<BufWriter<File>>>),
+    Noop,
+}
+
+/// cheap to clone; clones share the same backend
+#[derive(Clone)]
+pub struct MetricsSink {
+    backend: Backend,
+    run: Option<Arc<str>>,
+    /// prometheus registry served at --metrics-listen, if enabled
+    exporter: Option<Arc<Registry>>,
+}
+
+impl MetricsSink {
+    pub fn new(opts: &MetricsOpts) -> Self {
+        let backend = match opts.metrics_sink {
+            SinkKind::Influx => Backend::Influx(InfluxWriter::new(&opts.influx_host, &opts.influx_db)),
+
+            SinkKind::File => {
+                let file = File::create(&opts.metrics_file)
+                    .unwrap_or_else(|e| panic!("failed to create --metrics-file {}: {}", opts.metrics_file.display(), e));
+                println!("writing metrics to {}", opts.metrics_file.display());
+                Backend::File(Arc::new(Mutex::new(BufWriter::new(file))))
+            }
+
+            SinkKind::Prometheus | SinkKind::None => Backend::Noop,
+        };
+        let exporter = match opts.serve_metrics || opts.metrics_sink == SinkKind::Prometheus {
+            true => {
+                let registry = Arc::new(Registry::new(opts.run_tag.clone()));
+                registry.serve(opts.metrics_listen);
+                Some(registry)
+            }
+            false => None,
+        };
+        Self { backend, run: opts.run_tag.as_deref().map(Arc::from), exporter }
+    }
+
+    /// a completed (or failed) api request. `status` is the http status code, or "timeout" /
+    /// "malformed" (in which case `timeout_phase` may say which deadline was missed)
+    pub fn api_req(&self, endpoint: &str, status: &str, connection_mode: &str, timeout_phase: Option<&str>, took: Duration) {
+        let took_ns = took.as_nanos() as i64;
+        match &self.backend {
+            Backend::Influx(influx) => {
+                let took = took_ns;
+                let timeout_phase = timeout_phase.unwrap_or(NO_TAG);
+                let run = self.run_tag();
+                measure!(influx, api_req, t(endpoint), t(status), t(connection_mode), t(timeout_phase), t(run), i(took), tm(now()))
+            }
+
+            Backend::File(_) => {
+                let mut tags = vec![("endpoint", endpoint), ("status", status), ("connection_mode", connection_mode)];
+                if let Some(phase) = timeout_phase { tags.push(("timeout_phase", phase)); }
+                self.write_line("api_req", &tags, &[("took", took_ns)]);
+            }
+
+            Backend::Noop => {}
+        }
+        if let Some(registry) = self.exporter.as_ref() {
+            registry.observe_request(endpoint, status, took);
+        }
+    }
+
+    /// cpu usage of this process over the last interval, as a percentage of one core
+    pub fn harness_cpu(&self, harness_cpu_pct: i64) {
+        match &self.backend {
+            Backend::Influx(influx) => {
+                let run = self.run_tag();
+                measure!(influx, harness_cpu, t(run), i(harness_cpu_pct), tm(now()))
+            }
+
+            Backend::File(_) => self.write_line("harness_cpu", &[], &[("harness_cpu_pct", harness_cpu_pct)]),
+
+            Backend::Noop => {}
+        }
+        if let Some(registry) = self.exporter.as_ref() {
+            registry.set_gauge("harness_cpu_pct", "cpu usage of the harness process, % of one core", harness_cpu_pct as f64);
+        }
+    }
+
+    /// open-loop dispatcher state (see `load::Schedule`)
+    pub fn schedule(&self, backlog: usize, dropped: usize, lag: Duration, target_rate: f64) {
+        let backlog = backlog as i64;
+        let dropped = dropped as i64;
+        let lag = lag.as_nanos() as i64;
+        let target_rate = target_rate.round() as i64;
+        match &self.backend {
+            Backend::Influx(influx) => {
+                let run = self.run_tag();
+                measure!(influx, schedule, t(run), i(backlog), i(dropped), i(lag), i(target_rate), tm(now()))
+            }
+
+            Backend::File(_) => {
+                self.write_line("schedule", &[], &[("backlog", backlog), ("dropped", dropped), ("lag", lag), ("target_rate", target_rate)]);
+            }
+
+            Backend::Noop => {}
+        }
+        if let Some(registry) = self.exporter.as_ref() {
+            registry.set_gauge("schedule_backlog", "jobs waiting for a free worker", backlog as f64);
+            registry.set_counter("schedule_dropped_total", "jobs dropped because the harness was behind schedule", dropped as f64);
+            registry.set_gauge("schedule_lag_seconds", "how far behind schedule the harness is", lag as f64 / 1e9);
+            registry.set_gauge("schedule_target_rate", "target request rate (req/s)", target_rate as f64);
+        }
+    }
+
+    /// running totals of a stress-test run, sent by the manager once per second
+    pub fn run_snapshot(&self, snap: &RunSnapshot) {
+        let n_read = snap.n_read as i64;
+        let n_write = snap.n_write as i64;
+        let workouts_pending = snap.workouts_pending as i64;
+        let workouts_confirmed = snap.workouts_confirmed as i64;
+        let n_timeouts = snap.n_timeouts as i64;
+        let n_malformed = snap.n_malformed as i64;
+        let n_errors = snap.n_errors as i64;
+        let n_verification_failures = snap.n_verification_failures as i64;
+        match &self.backend {
+            Backend::Influx(influx) => {
+                let run = self.run_tag();
+                measure!(influx, stress_test, t(run), i(n_read), i(n_write), i(workouts_pending), i(workouts_confirmed),
+                    i(n_timeouts), i(n_malformed), i(n_errors), i(n_verification_failures), tm(now()))
+            }
+
+            Backend::File(_) => {
+                self.write_line("stress_test", &[], &[
+                    ("n_read", n_read),
+                    ("n_write", n_write),
+                    ("workouts_pending", workouts_pending),
+                    ("workouts_confirmed", workouts_confirmed),
+                    ("n_timeouts", n_timeouts),
+                    ("n_malformed", n_malformed),
+                    ("n_errors", n_errors),
+                    ("n_verification_failures", n_verification_failures),
+                ]);
+            }
+
+            Backend::Noop => {}
+        }
+        if let Some(registry) = self.exporter.as_ref() {
+            registry.set_counter("jobs_read_total", "read jobs assigned to workers", snap.n_read as f64);
+            registry.set_counter("jobs_write_total", "write jobs assigned to workers", snap.n_write as f64);
+            registry.set_gauge("workouts_pending", "workouts sent in write jobs, including writes not yet completed", snap.workouts_pending as f64);
+            registry.set_gauge("workouts_confirmed", "workouts confirmed to have been inserted", snap.workouts_confirmed as f64);
+            registry.set_counter("timeouts_total", "requests that timed out", snap.n_timeouts as f64);
+            registry.set_counter("malformed_responses_total", "responses with malformed http framing or json", snap.n_malformed as f64);
+            registry.set_counter("errors_total", "failed requests of any class", snap.n_errors as f64);
+            registry.set_counter("verification_failures_total", "read checks that didn't match the workouts written", snap.n_verification_failures as f64);
+        }
+    }
+
+    /// resource usage of the api server process (see `resources`)
+    pub fn server_process(&self, sample: &ProcessSample) {
+        let rss_bytes = sample.rss_bytes as i64;
+        let cpu_pct = sample.cpu_pct.round() as i64;
+        let n_fds = sample.n_fds as i64;
+        let n_threads = sample.n_threads as i64;
+        match &self.backend {
+            Backend::Influx(influx) => {
+                let run = self.run_tag();
+                measure!(influx, server_process, t(run), i(rss_bytes), i(cpu_pct), i(n_fds), i(n_threads), tm(now()))
+            }
+
+            Backend::File(_) => {
+                self.write_line("server_process", &[], &[("rss_bytes", rss_bytes), ("cpu_pct", cpu_pct), ("n_fds", n_fds), ("n_threads", n_threads)]);
+            }
+
+            Backend::Noop => {}
+        }
+        if let Some(registry) = self.exporter.as_ref() {
+            registry.set_gauge("server_rss_bytes", "resident memory of the api server process", sample.rss_bytes as f64);
+            registry.set_counter("server_cpu_seconds_total", "cpu time used by the api server process", sample.cpu_time_secs);
+            registry.set_gauge("server_open_fds", "open file descriptors of the api server process", sample.n_fds as f64);
+            registry.set_gauge("server_threads", "threads of the api server process", sample.n_threads as f64);
+        }
+    }
+
+    /// postgres table stats and sizes (see `resources`)
+    pub fn server_db(&self, sample: &DbSample) {
+        let DbSample { n_live_tup, n_dead_tup, n_tup_ins, seq_scan, idx_scan, table_bytes, index_bytes, database_bytes, .. } = *sample;
+        match &self.backend {
+            Backend::Influx(influx) => {
+                let run = self.run_tag();
+                measure!(influx, server_db, t(run), i(n_live_tup), i(n_dead_tup), i(n_tup_ins), i(seq_scan), i(idx_scan),
+                    i(table_bytes), i(index_bytes), i(database_bytes), tm(now()))
+            }
+
+            Backend::File(_) => {
+                self.write_line("server_db", &[], &[
+                    ("n_live_tup", n_live_tup),
+                    ("n_dead_tup", n_dead_tup),
+                    ("n_tup_ins", n_tup_ins),
+                    ("seq_scan", seq_scan),
+                    ("idx_scan", idx_scan),
+                    ("table_bytes", table_bytes),
+                    ("index_bytes", index_bytes),
+                    ("database_bytes", database_bytes),
+                ]);
+            }
+
+            Backend::Noop => {}
+        }
+        if let Some(registry) = self.exporter.as_ref() {
+            registry.set_gauge("db_workouts_live_rows", "estimated live rows in the workouts table", n_live_tup as f64);
+            registry.set_gauge("db_workouts_dead_rows", "estimated dead rows in the workouts table", n_dead_tup as f64);
+            registry.set_counter("db_workouts_inserted_rows_total", "rows inserted into the workouts table (pg_stat_user_tables)", n_tup_ins as f64);
+            registry.set_counter("db_workouts_seq_scans_total", "sequential scans of the workouts table", seq_scan as f64);
+            registry.set_counter("db_workouts_idx_scans_total", "index scans of the workouts table", idx_scan as f64);
+            registry.set_gauge("db_workouts_table_bytes", "size of the workouts table, excluding indexes", table_bytes as f64);
+            registry.set_gauge("db_workouts_index_bytes", "size of the indexes on the workouts table", index_bytes as f64);
+            registry.set_gauge("db_database_bytes", "size of the database", database_bytes as f64);
+        }
+    }
+
+    /// writes out anything buffered (only the file backend buffers)
+    pub fn flush(&self) {
+        if let Backend::File(file) = &self.backend {
+            file.lock().unwrap().flush().expect("flush --metrics-file");
+        }
+    }
+
+    fn run_tag(&self) -> &str {
+        self.run.as_deref().unwrap_or(NO_TAG)
+    }
+
+    fn write_line(&self, measurement: &str, tags: &[(&str, &str)], fields: &[(&str, i64)]) {
+        let file = match &self.backend {
+            Backend::File(file) => file,
+            _ => return,
+        };
+        let mut line = String::from(measurement);
+        for (k, v) in tags.iter() {
+            line.push_str(&format!(",{}={}", k, escape_tag(v)));
+        }
+        if let Some(run) = self.run.as_deref() {
+            line.push_str(&format!(",run={}", escape_tag(run)));
+        }
+        for (i, (k, v)) in fields.iter().enumerate() {
+            line.push(if i == 0 { ' ' } else { ',' });
+            line.push_str(&format!("{}={}i", k, v));
+        }
+        line.push_str(&format!(" {}\n", now()));
+        file.lock().unwrap().write_all(line.as_bytes()).expect("write --metrics-file");
+    }
+}
+
+/// value of an influx tag that doesn't apply to a measurement (or of `run` without
+/// --run-tag), so that every measurement has the same tag set
+const NO_TAG: &str = "none";
+
+fn now() -> i64 {
+    Utc::now().timestamp_nanos()
+}
+
+/// escapes a tag value per the influx line protocol
+fn escape_tag(s: &str) -> String {
+    s.replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// lines written by a file sink (with `run_tag`) after calling `write` on it
+    fn written(run_tag: Option<&str>, write: impl FnOnce(&MetricsSink)) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!("fitbod-test-metrics-{}.lp", uuid::Uuid::new_v4()));
+        let mut args = vec!["test".to_string(), "--metrics-sink".into(), "file".into(), "--metrics-file".into(), path.display().to_string()];
+        if let Some(run) = run_tag { args.extend(vec!["--run-tag".to_string(), run.to_string()]); }
+        let sink = MetricsSink::new(&MetricsOpts::from_iter_safe(&args).unwrap());
+        write(&sink);
+        sink.flush();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(text.ends_with('\n'));
+        text.lines().map(String::from).collect()
+    }
+
+    /// the line without its timestamp, after checking the timestamp is in nanoseconds and recent
+    fn strip_timestamp(line: &str) -> &str {
+        let i = line.rfind(' ').unwrap();
+        let ts: i64 = line[i + 1..].parse().unwrap_or_else(|_| panic!("no timestamp: {}", line));
+        assert!((now() - ts).abs() < 60_000_000_000, "timestamp {} not recent", ts);
+        &line[..i]
+    }
+
+    #[test]
+    fn tags_are_escaped() {
+        assert_eq!(escape_tag("a,b c=d"), "a\\,b\\ c\\=d");
+        let lines = written(Some("night run,v=2"), |sink| {
+            sink.api_req("/api/v1/workouts/list", "200", "keep-alive", None, Duration::from_micros(1500));
+        });
+        assert_eq!(lines.len(), 1);
+        assert_eq!(strip_timestamp(&lines[0]),
+            "api_req,endpoint=/api/v1/workouts/list,status=200,connection_mode=keep-alive,run=night\\ run\\,v\\=2 took=1500000i");
+    }
+
+    #[test]
+    fn fields_are_integers() {
+        let lines = written(None, |sink| {
+            sink.api_req("/api/v1/workouts/new", "timeout", "per-request", Some("read"), Duration::from_millis(2));
+            sink.harness_cpu(37);
+        });
+        assert_eq!(lines.len(), 2);
+        assert_eq!(strip_timestamp(&lines[0]),
+            "api_req,endpoint=/api/v1/workouts/new,status=timeout,connection_mode=per-request,timeout_phase=read took=2000000i");
+        assert_eq!(strip_timestamp(&lines[1]), "harness_cpu harness_cpu_pct=37i");
+    }
+}
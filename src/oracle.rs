@@ -0,0 +1,382 @@
+//! reference model of /api/v1/workouts/list
+//!
+//! given the workouts written for a user, `expected` predicts the exact response the server
+//! should return for a request, and `diff` compares it to the actual response item by item,
+//! so that wrong order, duplicates, truncation and corrupted fields are all caught (not just
+//! a different set of `workout_id`s).
+//!
+//! the contract it checks (`LIST_ORDER`, and the `[start, end)` window in `ListWindow`) is
+//! not specified anywhere in this repo. it's what fitbod-server's list query is understood
+//! to do: `where start_time >= start and start_time < end order by start_time desc limit n`.
+//! if the server's semantics change, this module is the one place to update. ties in
+//! `start_time` shouldn't occur at all, given the (user_id, start_time) unique key on
+//! workouts (see insert-workouts-test), but are handled deterministically anyway.
+
+use std::fmt;
+use chrono::{DateTime, Utc};
+use hashbrown::HashMap;
+use rand::Rng;
+use uuid::Uuid;
+
+/// order /api/v1/workouts/list is expected to return workouts in. workouts with the same
+/// `start_time` may be returned in any order, but where a limit cuts between them, the
+/// ones with the lowest `workout_id` are expected to be kept.
+pub const LIST_ORDER: &str = "newest start_time first";
+
+/// filters sent in a /api/v1/workouts/list request. workouts are expected to be returned if
+/// `start <= start_time < end`, in `LIST_ORDER`, up to `limit` of them. without a limit,
+/// every matching workout is expected.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListWindow {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl ListWindow {
+    /// a random window over `workouts` (sorted by `start_time`). each bound is open half the
+    /// time, and `limit` is random unless given.
+    pub fn random<R: Rng>(rng: &mut R, workouts: &[fitbod::Workout], limit: Option<usize>) -> Self {
+        if workouts.is_empty() {
+            return Self { limit, ..Default::default() }
+        }
+        let (a, b) = (rng.gen_range(0..workouts.len()), rng.gen_range(0..workouts.len()));
+        let (lo, hi) = (a.min(b), a.max(b));
+        let start = Some(workouts[lo].start_time).filter(|_| rng.gen_bool(0.5));
+        let end = Some(workouts[hi].start_time).filter(|_| rng.gen_bool(0.5));
+        let limit = limit.or_else(|| Some(rng.gen_range(1..=workouts.len())).filter(|_| rng.gen_bool(0.5)));
+        Self { start, end, limit }
+    }
+
+    pub fn contains(&self, start_time: DateTime<Utc>) -> bool {
+        self.start.map(|x| start_time >= x).unwrap_or(true) && self.end.map(|x| start_time < x).unwrap_or(true)
+    }
+
+    pub fn request(&self, user_id: Uuid) -> fitbod::api::ListWorkoutsRequest {
+        fitbod::api::ListWorkoutsRequest { user_id, start: self.start, end: self.end, limit: self.limit }
+    }
+}
+
+/// the response the server should return for `window`, if it stores exactly `workouts`
+pub fn expected<'a, I>(workouts: I, window: &ListWindow) -> Vec<fitbod::Workout>
+    where I: IntoIterator<Item = &'a fitbod::Workout>
+{
+    let mut out: Vec<fitbod::Workout> = workouts.into_iter()
+        .filter(|x| window.contains(x.start_time))
+        .cloned()
+        .collect();
+    out.sort_unstable_by(|a, b| b.start_time.cmp(&a.start_time).then_with(|| a.workout_id.cmp(&b.workout_id)));
+    if let Some(limit) = window.limit {
+        out.truncate(limit);
+    }
+    out
+}
+
+/// one difference between the expected and actual response. positions are indexes into
+/// the respective `items` arrays.
+#[derive(Debug, Clone)]
+pub enum Mismatch {
+    /// expected workout not in the response
+    Missing { pos: usize, expected: fitbod::Workout },
+    /// workout in the response that shouldn't be (unknown, outside the window, or beyond
+    /// the limit)
+    Unexpected { pos: usize, actual: fitbod::Workout },
+    /// unknown workout belonging to a different user than the one requested
+    OtherUser { pos: usize, actual: fitbod::Workout },
+    /// workout returned a second time
+    Duplicate { pos: usize, first_pos: usize, workout_id: Uuid },
+    /// workout returned before one that should come ahead of it
+    OutOfOrder { pos: usize, expected_pos: usize, workout_id: Uuid },
+    /// workout returned with a field that differs from what was written, and the likely
+    /// cause, if recognized (see `time_corruption`)
+    Field { pos: usize, workout_id: Uuid, field: &'static str, expected: String, actual: String, cause: Option<&'static str> },
+}
+
+impl Mismatch {
+    /// whether the server returned the workout with different contents than were written
+    /// (as opposed to returning the wrong set of workouts, or in the wrong order)
+    pub fn is_corruption(&self) -> bool {
+        matches!(self, Mismatch::Field { .. } | Mismatch::OtherUser { .. })
+    }
+
+    /// short description used to count mismatches, e.g. "start_time shifted by whole hours"
+    pub fn class(&self) -> String {
+        match self {
+            Mismatch::Missing { .. } => "missing workout".to_string(),
+            Mismatch::Unexpected { .. } => "unexpected workout".to_string(),
+            Mismatch::OtherUser { .. } => "another user's workout".to_string(),
+            Mismatch::Duplicate { .. } => "duplicate workout".to_string(),
+            Mismatch::OutOfOrder { .. } => "out of order".to_string(),
+            Mismatch::Field { field, cause, .. } => format!("{} {}", field, cause.unwrap_or("mismatch")),
+        }
+    }
+}
+
+/// recognizes the round-trip bugs a time field is most likely to suffer: a timezone applied
+/// (or not) on one side only, or precision lost in storage
+fn time_corruption(expected: DateTime<Utc>, actual: DateTime<Utc>) -> Option<&'static str> {
+    let delta = actual.signed_duration_since(expected);
+    if delta == chrono::Duration::zero() { return None }
+    if delta.num_milliseconds() % (60 * 60 * 1000) == 0 && delta.num_hours().abs() <= 26 {
+        return Some("shifted by whole hours")
+    }
+    if delta.num_seconds() == 0 {
+        return Some("sub-second precision lost")
+    }
+    None
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Missing { pos, expected } => write!(f, "- [{}] {} {} .. {} (missing)",
+                pos, expected.workout_id, expected.start_time, expected.end_time),
+            Mismatch::Unexpected { pos, actual } => write!(f, "+ [{}] {} {} .. {} (unexpected)",
+                pos, actual.workout_id, actual.start_time, actual.end_time),
+            Mismatch::OtherUser { pos, actual } => write!(f, "+ [{}] {} {} .. {} (belongs to user id {})",
+                pos, actual.workout_id, actual.start_time, actual.end_time, actual.user_id),
+            Mismatch::Duplicate { pos, first_pos, workout_id } => write!(f, "+ [{}] {} (duplicate of [{}])",
+                pos, workout_id, first_pos),
+            Mismatch::OutOfOrder { pos, expected_pos, workout_id } => write!(f, "~ [{}] {} out of order (expected at [{}], {})",
+                pos, workout_id, expected_pos, LIST_ORDER),
+            Mismatch::Field { pos, workout_id, field, expected, actual, cause } => {
+                write!(f, "~ [{}] {} {}: expected {}, got {}", pos, workout_id, field, expected, actual)?;
+                match cause {
+                    Some(cause) => write!(f, " ({})", cause),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// compares the `actual` response items to the `expected` ones (see `expected`) for a list
+/// request by `user_id`. empty if they match.
+pub fn diff(user_id: Uuid, expected: &[fitbod::Workout], actual: &[fitbod::Workout]) -> Vec<Mismatch> {
+    let expected_pos: HashMap<Uuid, usize> = expected.iter().enumerate().map(|(i, x)| (x.workout_id, i)).collect();
+    // position of the first expected workout with the same start_time as each one (`expected`
+    // is sorted), so reordering within a group of equal start_times isn't flagged
+    let mut groups: Vec<usize> = Vec::with_capacity(expected.len());
+    for (i, x) in expected.iter().enumerate() {
+        let group = match i {
+            0 => 0,
+            _ if expected[i - 1].start_time == x.start_time => groups[i - 1],
+            _ => i,
+        };
+        groups.push(group);
+    }
+    let mut seen: HashMap<Uuid, usize> = HashMap::with_capacity(actual.len());
+    let mut out = Vec::new();
+    let mut last_group: Option<usize> = None;
+
+    for (pos, item) in actual.iter().enumerate() {
+        if let Some(&first_pos) = seen.get(&item.workout_id) {
+            out.push(Mismatch::Duplicate { pos, first_pos, workout_id: item.workout_id });
+            continue
+        }
+        seen.insert(item.workout_id, pos);
+
+        let i = match expected_pos.get(&item.workout_id) {
+            Some(&i) => i,
+            None if item.user_id != user_id => {
+                out.push(Mismatch::OtherUser { pos, actual: item.clone() });
+                continue
+            }
+            None => {
+                out.push(Mismatch::Unexpected { pos, actual: item.clone() });
+                continue
+            }
+        };
+        let want = &expected[i];
+        if last_group.map(|last| groups[i] < last).unwrap_or(false) {
+            out.push(Mismatch::OutOfOrder { pos, expected_pos: i, workout_id: item.workout_id });
+        } else {
+            last_group = Some(groups[i]);
+        }
+
+        let mut field = |name: &'static str, expected: String, actual: String, cause: Option<&'static str>| {
+            if expected != actual {
+                out.push(Mismatch::Field { pos, workout_id: item.workout_id, field: name, expected, actual, cause });
+            }
+        };
+        field("user_id", want.user_id.to_string(), item.user_id.to_string(), None);
+        field("start_time", want.start_time.to_rfc3339(), item.start_time.to_rfc3339(),
+            time_corruption(want.start_time, item.start_time));
+        field("end_time", want.end_time.to_rfc3339(), item.end_time.to_rfc3339(),
+            time_corruption(want.end_time, item.end_time));
+    }
+
+    for (pos, want) in expected.iter().enumerate() {
+        if ! seen.contains_key(&want.workout_id) {
+            out.push(Mismatch::Missing { pos, expected: want.clone() });
+        }
+    }
+    out
+}
+
+/// one line per mismatch, for printing under a failed check
+pub fn fmt_diff(mismatches: &[Mismatch]) -> String {
+    mismatches.iter().map(|x| format!("    {}\n", x)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn t(day: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_600_000_000 + day * 86_400, 0)
+    }
+
+    fn workout(user_id: Uuid, day: i64) -> fitbod::Workout {
+        fitbod::Workout { user_id, workout_id: Uuid::new_v4(), start_time: t(day), end_time: t(day) + chrono::Duration::minutes(45) }
+    }
+
+    fn ids(workouts: &[fitbod::Workout]) -> Vec<Uuid> {
+        workouts.iter().map(|x| x.workout_id).collect()
+    }
+
+    fn classes(mismatches: &[Mismatch]) -> Vec<String> {
+        mismatches.iter().map(|x| x.class()).collect()
+    }
+
+    #[test]
+    fn expected_is_newest_first() {
+        let user = Uuid::new_v4();
+        let (a, b, c) = (workout(user, 1), workout(user, 2), workout(user, 3));
+        let got = expected(vec![&b, &a, &c], &ListWindow::default());
+        assert_eq!(ids(&got), ids(&[c, b, a]));
+    }
+
+    #[test]
+    fn window_includes_start_and_excludes_end() {
+        let user = Uuid::new_v4();
+        let workouts: Vec<_> = (1..=5).map(|day| workout(user, day)).collect();
+        let window = ListWindow { start: Some(t(2)), end: Some(t(4)), limit: None };
+        assert!(! window.contains(t(1)));
+        assert!(window.contains(t(2)));
+        assert!(window.contains(t(3)));
+        assert!(! window.contains(t(4)));
+        assert_eq!(ids(&expected(&workouts, &window)), ids(&[workouts[2].clone(), workouts[1].clone()]));
+
+        let open_start = ListWindow { end: Some(t(2)), ..Default::default() };
+        assert_eq!(ids(&expected(&workouts, &open_start)), ids(&workouts[..1]));
+        let open_end = ListWindow { start: Some(t(5)), ..Default::default() };
+        assert_eq!(ids(&expected(&workouts, &open_end)), ids(&workouts[4..]));
+        let empty = ListWindow { start: Some(t(3)), end: Some(t(3)), limit: None };
+        assert!(expected(&workouts, &empty).is_empty());
+    }
+
+    #[test]
+    fn limit_keeps_newest() {
+        let user = Uuid::new_v4();
+        let workouts: Vec<_> = (1..=5).map(|day| workout(user, day)).collect();
+        let limit = |n| ListWindow { limit: Some(n), ..Default::default() };
+        assert_eq!(ids(&expected(&workouts, &limit(2))), ids(&[workouts[4].clone(), workouts[3].clone()]));
+        assert_eq!(expected(&workouts, &limit(5)).len(), 5);
+        assert_eq!(expected(&workouts, &limit(10)).len(), 5);
+        assert!(expected(&workouts, &limit(0)).is_empty());
+
+        // the limit applies after the window
+        let window = ListWindow { end: Some(t(4)), limit: Some(2), ..Default::default() };
+        assert_eq!(ids(&expected(&workouts, &window)), ids(&[workouts[2].clone(), workouts[1].clone()]));
+    }
+
+    #[test]
+    fn limit_cutting_equal_start_times_keeps_lowest_workout_id() {
+        let user = Uuid::new_v4();
+        let mut tied = [workout(user, 1), workout(user, 1), workout(user, 1)];
+        tied.sort_by_key(|x| x.workout_id);
+        let window = ListWindow { limit: Some(2), ..Default::default() };
+        assert_eq!(ids(&expected(tied.iter().rev(), &window)), ids(&tied[..2]));
+    }
+
+    #[test]
+    fn identical_response_has_no_mismatches() {
+        let user = Uuid::new_v4();
+        let want = expected(&[workout(user, 1), workout(user, 2)], &ListWindow::default());
+        assert!(diff(user, &want, &want).is_empty());
+        assert!(diff(user, &[], &[]).is_empty());
+    }
+
+    #[test]
+    fn missing_and_unexpected() {
+        let user = Uuid::new_v4();
+        let (a, b, c) = (workout(user, 3), workout(user, 2), workout(user, 1));
+        let got = diff(user, &[a.clone(), b.clone()], &[a, c.clone()]);
+        assert_eq!(classes(&got), vec!["unexpected workout", "missing workout"]);
+        assert!(matches!(&got[0], Mismatch::Unexpected { pos: 1, actual } if actual.workout_id == c.workout_id));
+        assert!(matches!(&got[1], Mismatch::Missing { pos: 1, expected } if expected.workout_id == b.workout_id));
+    }
+
+    #[test]
+    fn unknown_workout_of_another_user() {
+        let (user, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let a = workout(user, 2);
+        let got = diff(user, std::slice::from_ref(&a), &[a.clone(), workout(other, 1)]);
+        assert_eq!(classes(&got), vec!["another user's workout"]);
+        assert!(got[0].is_corruption());
+    }
+
+    #[test]
+    fn duplicate() {
+        let user = Uuid::new_v4();
+        let (a, b) = (workout(user, 2), workout(user, 1));
+        let got = diff(user, &[a.clone(), b.clone()], &[a.clone(), b, a.clone()]);
+        assert_eq!(got.len(), 1);
+        assert!(matches!(&got[0], Mismatch::Duplicate { pos: 2, first_pos: 0, workout_id } if *workout_id == a.workout_id));
+    }
+
+    #[test]
+    fn out_of_order() {
+        let user = Uuid::new_v4();
+        let (a, b, c) = (workout(user, 3), workout(user, 2), workout(user, 1));
+        // oldest first
+        let got = diff(user, &[a.clone(), b.clone(), c.clone()], &[c, b, a]);
+        assert_eq!(classes(&got), vec!["out of order", "out of order"]);
+        assert!(matches!(&got[0], Mismatch::OutOfOrder { pos: 1, expected_pos: 1, .. }));
+        assert!(matches!(&got[1], Mismatch::OutOfOrder { pos: 2, expected_pos: 0, .. }));
+    }
+
+    #[test]
+    fn equal_start_times_in_any_order() {
+        let user = Uuid::new_v4();
+        let (a, b, c, d) = (workout(user, 3), workout(user, 2), workout(user, 2), workout(user, 1));
+        let want = expected(vec![&a, &b, &c, &d], &ListWindow::default());
+        let swapped = vec![want[0].clone(), want[2].clone(), want[1].clone(), want[3].clone()];
+        assert!(diff(user, &want, &swapped).is_empty());
+
+        // but not ahead of a newer workout, or after an older one
+        let early = vec![want[1].clone(), want[0].clone(), want[2].clone(), want[3].clone()];
+        assert_eq!(classes(&diff(user, &want, &early)), vec!["out of order"]);
+        let late = vec![want[0].clone(), want[1].clone(), want[3].clone(), want[2].clone()];
+        assert_eq!(classes(&diff(user, &want, &late)), vec!["out of order"]);
+    }
+
+    #[test]
+    fn corrupted_fields() {
+        let user = Uuid::new_v4();
+        let a = workout(user, 1);
+        let mut shifted = a.clone();
+        shifted.start_time += chrono::Duration::hours(7);
+        shifted.end_time += chrono::Duration::milliseconds(250);
+        let got = diff(user, &[a], &[shifted]);
+        assert_eq!(classes(&got), vec!["start_time shifted by whole hours", "end_time sub-second precision lost"]);
+        assert!(got.iter().all(|x| x.is_corruption()));
+    }
+
+    #[test]
+    fn random_window_is_within_workouts() {
+        let user = Uuid::new_v4();
+        let workouts: Vec<_> = (1..=10).map(|day| workout(user, day)).collect();
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let window = ListWindow::random(&mut rng, &workouts, None);
+            if let (Some(start), Some(end)) = (window.start, window.end) {
+                assert!(start <= end);
+            }
+            assert!(window.limit.map(|x| (1..=10).contains(&x)).unwrap_or(true));
+        }
+        assert_eq!(ListWindow::random(&mut rng, &[], Some(3)).limit, Some(3));
+    }
+}
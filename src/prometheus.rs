@@ -0,0 +1,225 @@
+//! minimal prometheus exporter: a registry of metrics rendered in the text exposition format,
+//! served at `/metrics` by a background thread
+
+use std::time::*;
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::fmt::Write as _;
+use hashbrown::HashMap;
+
+const N_BUCKETS: usize = 13;
+
+/// upper bounds (seconds) of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; N_BUCKETS] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// scrape requests with a head larger than this are rejected
+const MAX_SCRAPE_REQUEST_LEN: usize = 8192;
+
+#[derive(Default)]
+struct RequestStats {
+    count: u64,
+    sum_secs: f64,
+    /// count of requests at or below each of `LATENCY_BUCKETS` (cumulative)
+    buckets: [u64; N_BUCKETS],
+}
+
+#[derive(Default)]
+struct Inner {
+    /// keyed by (endpoint, status)
+    requests: HashMap<(String, String), RequestStats>,
+    /// keyed by name; (help, type, value)
+    values: HashMap<&'static str, (&'static str, &'static str, f64)>,
+}
+
+/// metrics exposed on `/metrics`. all series are prefixed with `fitbod_test_`.
+pub struct Registry {
+    /// optional `run` label added to every series
+    run: Option<String>,
+    inner: Mutex<Inner>,
+}
+
+impl Registry {
+    pub fn new(run: Option<String>) -> Self {
+        Self { run, inner: Default::default() }
+    }
+
+    pub fn observe_request(&self, endpoint: &str, status: &str, took: Duration) {
+        let secs = took.as_secs_f64();
+        let mut inner = self.inner.lock().unwrap();
+        let stats = inner.requests.entry((endpoint.to_string(), status.to_string())).or_default();
+        stats.count += 1;
+        stats.sum_secs += secs;
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *le { stats.buckets[i] += 1; }
+        }
+    }
+
+    pub fn set_gauge(&self, name: &'static str, help: &'static str, value: f64) {
+        self.inner.lock().unwrap().values.insert(name, (help, "gauge", value));
+    }
+
+    /// sets a counter to a running total kept elsewhere (e.g. by the stress-test manager)
+    pub fn set_counter(&self, name: &'static str, help: &'static str, value: f64) {
+        self.inner.lock().unwrap().values.insert(name, (help, "counter", value));
+    }
+
+    /// renders every metric in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let run = match self.run.as_ref() {
+            Some(run) => format!(",run=\"{}\"", escape(run)),
+            None => String::new(),
+        };
+        let mut out = String::new();
+
+        let mut requests: Vec<_> = inner.requests.iter().collect();
+        requests.sort_by(|a, b| a.0.cmp(b.0));
+
+        out.push_str("# HELP fitbod_test_requests_total api requests sent, by endpoint and response status\n");
+        out.push_str("# TYPE fitbod_test_requests_total counter\n");
+        for ((endpoint, status), stats) in requests.iter() {
+            let _ = writeln!(out, "fitbod_test_requests_total{{endpoint=\"{}\",status=\"{}\"{}}} {}",
+                escape(endpoint), escape(status), run, stats.count);
+        }
+
+        out.push_str("# HELP fitbod_test_request_duration_seconds api request latency\n");
+        out.push_str("# TYPE fitbod_test_request_duration_seconds histogram\n");
+        for ((endpoint, status), stats) in requests.iter() {
+            let labels = format!("endpoint=\"{}\",status=\"{}\"{}", escape(endpoint), escape(status), run);
+            for (le, n) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter()) {
+                let _ = writeln!(out, "fitbod_test_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, n);
+            }
+            let _ = writeln!(out, "fitbod_test_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, stats.count);
+            let _ = writeln!(out, "fitbod_test_request_duration_seconds_sum{{{}}} {}", labels, stats.sum_secs);
+            let _ = writeln!(out, "fitbod_test_request_duration_seconds_count{{{}}} {}", labels, stats.count);
+        }
+
+        let labels = match self.run.as_ref() {
+            Some(run) => format!("{{run=\"{}\"}}", escape(run)),
+            None => String::new(),
+        };
+        let mut values: Vec<_> = inner.values.iter().collect();
+        values.sort_by_key(|(name, _)| **name);
+        for (name, (help, kind, value)) in values {
+            let _ = writeln!(out, "# HELP fitbod_test_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE fitbod_test_{} {}", name, kind);
+            let _ = writeln!(out, "fitbod_test_{}{} {}", name, labels, value);
+        }
+        out
+    }
+
+    /// serves `/metrics` on `addr` from a background thread
+    pub fn serve(self: &Arc<Self>, addr: SocketAddr) {
+        let listener = TcpListener::bind(addr)
+            .unwrap_or_else(|e| panic!("failed to bind metrics listener to {}: {}", addr, e));
+        println!("serving prometheus metrics at http://{}/metrics", addr);
+        let registry = Arc::clone(self);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                if let Err(e) = handle_scrape(stream, &registry) {
+                    eprintln!("metrics scrape failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+fn handle_scrape(mut stream: TcpStream, registry: &Registry) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while ! buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk)?;
+        if n == 0 || buf.len() + n > MAX_SCRAPE_REQUEST_LEN { return Ok(()) }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request_line = buf.split(|&b| b == b'\r').next().unwrap_or(&[]);
+    let (status, content_type, body) = if request_line.starts_with(b"GET /metrics ") {
+        ("200 OK", "text/plain; version=0.0.4", registry.render())
+    } else {
+        ("404 Not Found", "text/plain", "not found\n".to_string())
+    };
+    write!(stream, "HTTP/1.1 {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body)?;
+    stream.flush()
+}
+
+/// escapes a label value
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn lines(registry: &Registry, prefix: &str) -> Vec<String> {
+        registry.render().lines().filter(|x| x.starts_with(prefix)).map(String::from).collect()
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let registry = Registry::new(None);
+        for took in &[ms(2), ms(4), ms(40), ms(20_000)] {
+            registry.observe_request("/api/v1/workouts/list", "200", *took);
+        }
+        let labels = "endpoint=\"/api/v1/workouts/list\",status=\"200\"";
+        let buckets = lines(&registry, "fitbod_test_request_duration_seconds_bucket");
+        assert_eq!(buckets.len(), N_BUCKETS + 1);
+        assert_eq!(buckets[0], format!("fitbod_test_request_duration_seconds_bucket{{{},le=\"0.001\"}} 0", labels));
+        assert_eq!(buckets[1], format!("fitbod_test_request_duration_seconds_bucket{{{},le=\"0.0025\"}} 1", labels));
+        assert_eq!(buckets[2], format!("fitbod_test_request_duration_seconds_bucket{{{},le=\"0.005\"}} 2", labels));
+        assert_eq!(buckets[5], format!("fitbod_test_request_duration_seconds_bucket{{{},le=\"0.05\"}} 3", labels));
+        // over the largest bucket, so only in +Inf
+        assert_eq!(buckets[N_BUCKETS - 1], format!("fitbod_test_request_duration_seconds_bucket{{{},le=\"10\"}} 3", labels));
+        assert_eq!(buckets[N_BUCKETS], format!("fitbod_test_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 4", labels));
+        let counts: Vec<u64> = buckets.iter().map(|x| x.rsplit(' ').next().unwrap().parse().unwrap()).collect();
+        assert!(counts.windows(2).all(|w| w[0] <= w[1]), "{:?}", counts);
+
+        assert_eq!(lines(&registry, "fitbod_test_request_duration_seconds_count"),
+            vec![format!("fitbod_test_request_duration_seconds_count{{{}}} 4", labels)]);
+        assert_eq!(lines(&registry, "fitbod_test_requests_total{"),
+            vec![format!("fitbod_test_requests_total{{{}}} 4", labels)]);
+    }
+
+    #[test]
+    fn run_label_is_on_every_series() {
+        let registry = Registry::new(Some("nightly".to_string()));
+        registry.observe_request("/api/v1/workouts/new", "204", ms(3));
+        registry.set_gauge("virtual_users", "virtual users running", 8.0);
+        registry.set_counter("jobs_total", "jobs completed", 12.0);
+        let labels = "endpoint=\"/api/v1/workouts/new\",status=\"204\",run=\"nightly\"";
+        assert_eq!(lines(&registry, "fitbod_test_request_duration_seconds_bucket")[N_BUCKETS],
+            format!("fitbod_test_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 1", labels));
+        assert_eq!(lines(&registry, "fitbod_test_request_duration_seconds_sum"),
+            vec![format!("fitbod_test_request_duration_seconds_sum{{{}}} 0.003", labels)]);
+        assert_eq!(lines(&registry, "fitbod_test_virtual_users"), vec!["fitbod_test_virtual_users{run=\"nightly\"} 8"]);
+        assert_eq!(lines(&registry, "fitbod_test_jobs_total"), vec!["fitbod_test_jobs_total{run=\"nightly\"} 12"]);
+        assert!(registry.render().contains("# TYPE fitbod_test_jobs_total counter\n"));
+
+        let registry = Registry::new(None);
+        registry.set_gauge("virtual_users", "virtual users running", 8.0);
+        assert_eq!(lines(&registry, "fitbod_test_virtual_users"), vec!["fitbod_test_virtual_users 8"]);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape(r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(escape("a\nb"), "a\\nb");
+        let registry = Registry::new(Some("say \"hi\"\n".to_string()));
+        registry.set_gauge("virtual_users", "virtual users running", 1.0);
+        assert_eq!(lines(&registry, "fitbod_test_virtual_users"), vec![r#"fitbod_test_virtual_users{run="say \"hi\"\n"} 1"#]);
+    }
+}
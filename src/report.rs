@@ -0,0 +1,253 @@
+//! machine-readable summary of a stress-test run, written to --report-path as json so that
+//! runs can be archived and compared (see `compare-runs`)
+
+use std::path::Path;
+use std::process::Command;
+use serde::{Serialize, Deserialize};
+use chrono::prelude::*;
+use uuid::Uuid;
+use pretty_toa::ThousandsSep;
+
+use crate::stats::Latencies;
+use crate::slo::SloResult;
+use crate::resources::{DbSample, ProcessSample, ResourceSummary};
+use crate::JobMixOpts;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunReport {
+    pub params: RunParams,
+    pub env: EnvInfo,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// seconds from the first job being assigned until the last one completed
+    pub duration_secs: f64,
+    pub stop_reason: String,
+    pub n_requests: usize,
+    /// whole-run request counts and latencies, per endpoint and status
+    pub endpoints: Vec<EndpointStats>,
+    /// workouts sent in write jobs, whether or not the write has completed
+    pub workouts_pending: usize,
+    /// workouts confirmed to have been inserted
+    pub workouts_confirmed: usize,
+    pub n_timeouts: usize,
+    pub n_malformed: usize,
+    /// failed requests of every class (including timeouts and malformed responses)
+    pub n_errors: usize,
+    /// failed requests by endpoint and failure class, most frequent first
+    pub failures: Vec<FailureCount>,
+    /// read checks whose workouts didn't match what was written, while running and in the
+    /// final check
+    pub n_verification_failures: usize,
+    /// `None` if the final check was skipped (--read-only, or the run was aborted)
+    pub verification: Option<Verification>,
+    /// consistency violations found in the --history-path history, if one was recorded
+    pub history_violations: Option<usize>,
+    /// results of the --verify-db check, if it ran
+    pub db_verification: Option<DbVerification>,
+    /// stats for each reporting interval (roughly one per second)
+    pub intervals: Vec<IntervalStats>,
+    /// results for each --slo
+    pub slos: Vec<SloResult>,
+    /// api server / postgres resource usage, if monitored (--server-pid, --monitor-db)
+    pub resources: Option<ResourceSummary>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunParams {
+    pub engine: String,
+    pub n_threads: usize,
+    pub virtual_users: Option<usize>,
+    pub batch_size: usize,
+    pub read_only: bool,
+    pub n_users: usize,
+    pub n_workout_templates: usize,
+    pub connect: String,
+    pub connection_mode: String,
+    pub pool_size: usize,
+    /// load profile in --profile syntax, if running in open-loop mode
+    pub load_profile: Option<String>,
+    /// read/write mix and job shape
+    pub job_mix: JobMixOpts,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EnvInfo {
+    pub hostname: Option<String>,
+    /// commit of the working directory this was run from, if it is a git repo
+    pub git_commit: Option<String>,
+    pub git_dirty: Option<bool>,
+    pub harness_version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EndpointStats {
+    pub endpoint: String,
+    /// http status code, or "timeout" / "malformed" / "transport"
+    pub status: String,
+    pub count: u64,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub p99_9_ms: f64,
+    pub p99_99_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IntervalStats {
+    /// seconds since the start of the run, at the end of the interval
+    pub elapsed_secs: f64,
+    pub duration_secs: f64,
+    pub n_jobs: usize,
+    pub n_read: usize,
+    pub n_write: usize,
+    pub workouts_confirmed: usize,
+    pub n_timeouts: usize,
+    pub harness_cpu_pct: f64,
+    pub endpoints: Vec<EndpointStats>,
+    /// api server process, sampled at the end of the interval
+    pub server: Option<ProcessSample>,
+    /// postgres sample taken during the interval, if any (these are less frequent)
+    pub db: Option<DbSample>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailureCount {
+    pub endpoint: String,
+    /// e.g. "timeout (read)", "connect error: connection refused", "http 500"
+    pub class: String,
+    pub count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Verification {
+    pub n_users_checked: usize,
+    pub failed_user_ids: Vec<Uuid>,
+}
+
+/// --verify-db result for one user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DbUserCheck {
+    pub user_id: Uuid,
+    /// rows in the workouts table
+    pub n_rows: usize,
+    /// workouts written (and acknowledged) but not stored
+    pub n_missing: usize,
+    /// rows that were never written, or stored more than once
+    pub n_extra: usize,
+    /// rows whose fields differ from what was written
+    pub n_mutated: usize,
+    /// whether the final check's list response differs from the stored rows
+    pub diverged: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DbVerification {
+    pub n_users_checked: usize,
+    pub n_rows: usize,
+    pub n_missing: usize,
+    pub n_extra: usize,
+    pub n_mutated: usize,
+    /// users with any missing, extra or mutated rows
+    pub failed_user_ids: Vec<Uuid>,
+    /// users whose list response differs from their stored rows
+    pub diverged_user_ids: Vec<Uuid>,
+}
+
+impl DbVerification {
+    pub fn new<I: IntoIterator<Item = DbUserCheck>>(checks: I) -> Self {
+        let mut out = Self::default();
+        for x in checks {
+            out.n_users_checked += 1;
+            out.n_rows += x.n_rows;
+            out.n_missing += x.n_missing;
+            out.n_extra += x.n_extra;
+            out.n_mutated += x.n_mutated;
+            if x.n_missing + x.n_extra + x.n_mutated > 0 {
+                out.failed_user_ids.push(x.user_id);
+            }
+            if x.diverged {
+                out.diverged_user_ids.push(x.user_id);
+            }
+        }
+        out
+    }
+
+    pub fn is_failed(&self) -> bool {
+        ! self.failed_user_ids.is_empty() || ! self.diverged_user_ids.is_empty()
+    }
+
+    pub fn fmt_line(&self) -> String {
+        format!("db check: {} rows for {} users - {} missing - {} extra - {} mutated - {} users failed - api differs from db for {} users",
+            self.n_rows.thousands_sep(),
+            self.n_users_checked.thousands_sep(),
+            self.n_missing.thousands_sep(),
+            self.n_extra.thousands_sep(),
+            self.n_mutated.thousands_sep(),
+            self.failed_user_ids.len().thousands_sep(),
+            self.diverged_user_ids.len().thousands_sep(),
+        )
+    }
+}
+
+impl EndpointStats {
+    /// one entry per endpoint/status with any samples
+    pub fn from_latencies(latencies: &Latencies) -> Vec<EndpointStats> {
+        let ms = |us: u64| us as f64 / 1000.0;
+        latencies.iter()
+            .map(|(endpoint, status, h)| EndpointStats {
+                endpoint: endpoint.to_string(),
+                status: status.to_string(),
+                count: h.len(),
+                min_ms: ms(h.min()),
+                mean_ms: h.mean() / 1000.0,
+                p50_ms: ms(h.value_at_percentile(50.0)),
+                p90_ms: ms(h.value_at_percentile(90.0)),
+                p99_ms: ms(h.value_at_percentile(99.0)),
+                p99_9_ms: ms(h.value_at_percentile(99.9)),
+                p99_99_ms: ms(h.value_at_percentile(99.99)),
+                max_ms: ms(h.max()),
+            }).collect()
+    }
+}
+
+impl EnvInfo {
+    pub fn collect() -> Self {
+        let git = |args: &[&str]| -> Option<String> {
+            let out = Command::new("git").args(args).output().ok()?;
+            if ! out.status.success() { return None }
+            Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+        };
+        EnvInfo {
+            hostname: hostname(),
+            git_commit: git(&["rev-parse", "HEAD"]),
+            git_dirty: git(&["status", "--porcelain"]).map(|x| ! x.is_empty()),
+            harness_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 { return None }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+impl RunReport {
+    pub fn save(&self, path: &Path) {
+        let file = std::fs::File::create(path)
+            .unwrap_or_else(|e| panic!("failed to create --report-path {}: {}", path.display(), e));
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self).unwrap();
+    }
+
+    pub fn load(path: &Path) -> Self {
+        let bytes = std::fs::read(path)
+            .unwrap_or_else(|e| panic!("failed to read run report {}: {}", path.display(), e));
+        serde_json::from_slice(&bytes[..])
+            .unwrap_or_else(|e| panic!("failed to parse run report {}: {}", path.display(), e))
+    }
+}
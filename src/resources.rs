@@ -0,0 +1,287 @@
+//! resource usage of the system under test, sampled while stress-test runs
+//!
+//! the api server process is sampled from /proc (linux only) at the end of each reporting
+//! interval. postgres is queried from a background thread, since the queries can take a
+//! while on a large table and the manager loop must not stall.
+
+use std::time::*;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use serde::{Serialize, Deserialize};
+use structopt::StructOpt;
+use pretty_toa::ThousandsSep;
+
+/// table whose size is tracked when --monitor-db is set (and checked with --verify-db)
+pub const WORKOUTS_TABLE: &str = "workouts";
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct ResourceOpts {
+    /// pid of the api server, whose memory, cpu, open fds and thread count are sampled
+    /// from /proc every second
+    #[structopt(long, conflicts_with = "server-process")]
+    pub server_pid: Option<u32>,
+
+    /// like --server-pid, but finds the process by name (e.g. fitbod-server). there must be
+    /// exactly one match.
+    #[structopt(long)]
+    pub server_process: Option<String>,
+
+    /// sample postgres table stats and the size of the workouts table (and its indexes)
+    /// via DATABASE_URL
+    #[structopt(long)]
+    pub monitor_db: bool,
+
+    /// how often postgres is sampled with --monitor-db
+    #[structopt(long, default_value = "10s", parse(try_from_str = humantime::parse_duration))]
+    pub db_sample_interval: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProcessSample {
+    pub rss_bytes: u64,
+    /// user + system cpu time since the process started
+    pub cpu_time_secs: f64,
+    /// cpu usage since the previous sample, as a percentage of one core
+    pub cpu_pct: f64,
+    pub n_fds: usize,
+    pub n_threads: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DbSample {
+    /// seconds since the start of the run
+    pub elapsed_secs: f64,
+    /// estimated rows, from pg_stat_user_tables
+    pub n_live_tup: i64,
+    pub n_dead_tup: i64,
+    pub n_tup_ins: i64,
+    pub seq_scan: i64,
+    pub idx_scan: i64,
+    pub table_bytes: i64,
+    pub index_bytes: i64,
+    pub database_bytes: i64,
+}
+
+/// summary for the run report
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResourceSummary {
+    pub server_pid: Option<u32>,
+    pub peak_rss_bytes: Option<u64>,
+    pub last_process_sample: Option<ProcessSample>,
+    pub last_db_sample: Option<DbSample>,
+}
+
+/// reads the state of one process from /proc
+struct ProcessSampler {
+    pid: u32,
+    clock_ticks_per_sec: f64,
+    page_size: u64,
+    /// (when, cpu time) of the previous sample
+    last: Option<(Instant, f64)>,
+}
+
+impl ProcessSampler {
+    fn new(pid: u32) -> Self {
+        let clock_ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        Self { pid, clock_ticks_per_sec, page_size, last: None }
+    }
+
+    /// `None` if the process can't be read (e.g. it exited)
+    fn sample(&mut self) -> Option<ProcessSample> {
+        let proc_dir = PathBuf::from(format!("/proc/{}", self.pid));
+        let now = Instant::now();
+
+        // the command name (2nd field) is in parentheses and may contain spaces, so fields
+        // are counted from the closing paren. utime and stime are the 14th and 15th fields.
+        let stat = std::fs::read_to_string(proc_dir.join("stat")).ok()?;
+        let fields: Vec<&str> = stat[(stat.rfind(')')? + 2)..].split_whitespace().collect();
+        let ticks = |i: usize| fields.get(i - 3).and_then(|x| x.parse::<u64>().ok());
+        let cpu_time_secs = (ticks(14)? + ticks(15)?) as f64 / self.clock_ticks_per_sec;
+        let n_threads = fields.get(20 - 3)?.parse().ok()?;
+
+        // second field of statm is resident pages
+        let statm = std::fs::read_to_string(proc_dir.join("statm")).ok()?;
+        let rss_pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+
+        let n_fds = std::fs::read_dir(proc_dir.join("fd")).ok()?.count();
+
+        let cpu_pct = match self.last {
+            Some((then, prev)) => (cpu_time_secs - prev) / now.saturating_duration_since(then).as_secs_f64() * 100.0,
+            None => 0.0,
+        };
+        self.last = Some((now, cpu_time_secs));
+
+        Some(ProcessSample { rss_bytes: rss_pages * self.page_size, cpu_time_secs, cpu_pct, n_fds, n_threads })
+    }
+}
+
+/// finds the pid of the one running process named `name` (matched against the first
+/// element of its command line, or its command name)
+fn find_process(name: &str) -> u32 {
+    let mut found: Vec<(u32, String)> = Vec::new();
+    for entry in std::fs::read_dir("/proc").expect("read /proc") {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let pid: u32 = match entry.file_name().to_str().and_then(|x| x.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid == std::process::id() { continue }
+        let cmdline = std::fs::read(entry.path().join("cmdline")).unwrap_or_default();
+        let argv0 = cmdline.split(|&b| b == 0).next().map(|x| String::from_utf8_lossy(x).into_owned()).unwrap_or_default();
+        let comm = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+        let exe_name = argv0.rsplit('/').next().unwrap_or("");
+        if exe_name == name || comm.trim_end() == name {
+            found.push((pid, argv0));
+        }
+    }
+    match found.len() {
+        1 => found[0].0,
+        0 => panic!("no running process matches --server-process {}", name),
+        _ => panic!("more than one process matches --server-process {}: {:?}", name, found),
+    }
+}
+
+async fn sample_db(pool: &sqlx::PgPool, elapsed: Duration) -> Result<DbSample, sqlx::Error> {
+    let (n_live_tup, n_dead_tup, n_tup_ins, seq_scan, idx_scan): (i64, i64, i64, i64, i64) = sqlx::query_as(
+        "select n_live_tup, n_dead_tup, n_tup_ins, coalesce(seq_scan, 0), coalesce(idx_scan, 0) \
+         from pg_stat_user_tables where relname = $1")
+        .bind(WORKOUTS_TABLE)
+        .fetch_one(pool)
+        .await?;
+    let (table_bytes, index_bytes, database_bytes): (i64, i64, i64) = sqlx::query_as(
+        "select pg_table_size($1::regclass), pg_indexes_size($1::regclass), pg_database_size(current_database())")
+        .bind(WORKOUTS_TABLE)
+        .fetch_one(pool)
+        .await?;
+    Ok(DbSample {
+        elapsed_secs: elapsed.as_secs_f64(),
+        n_live_tup,
+        n_dead_tup,
+        n_tup_ins,
+        seq_scan,
+        idx_scan,
+        table_bytes,
+        index_bytes,
+        database_bytes,
+    })
+}
+
+/// samples the api server process and postgres, per --server-pid/--server-process and
+/// --monitor-db
+pub struct ResourceMonitor {
+    process: Option<ProcessSampler>,
+    /// most recent postgres sample, updated by the background thread. taken by `sample_db`
+    /// so each one is only reported once.
+    db: Option<Arc<Mutex<Option<DbSample>>>>,
+    summary: ResourceSummary,
+}
+
+impl ResourceMonitor {
+    pub fn new(opts: &ResourceOpts, run_start: Instant) -> Self {
+        let pid = opts.server_pid.or_else(|| opts.server_process.as_deref().map(find_process));
+        let process = pid.map(|pid| {
+            let mut sampler = ProcessSampler::new(pid);
+            if sampler.sample().is_none() {
+                panic!("failed to read /proc/{} (is the api server running?)", pid);
+            }
+            println!("monitoring api server process {}", pid);
+            sampler
+        });
+
+        let db = match opts.monitor_db {
+            true => {
+                let db_url = std::env::var("DATABASE_URL").expect("--monitor-db requires DATABASE_URL");
+                let latest: Arc<Mutex<Option<DbSample>>> = Default::default();
+                let shared = Arc::clone(&latest);
+                let interval = opts.db_sample_interval;
+                // connect up front, so a bad DATABASE_URL stops the run before it starts
+                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+                let db = rt.block_on(fitbod::db::DataBase::new(&db_url))
+                    .unwrap_or_else(|e| panic!("--monitor-db failed to connect to DATABASE_URL: {}", e));
+                std::thread::spawn(move || {
+                    rt.block_on(async {
+                        let mut ticker = tokio::time::interval(interval);
+                        loop {
+                            ticker.tick().await;
+                            match sample_db(db.pool(), run_start.elapsed()).await {
+                                Ok(sample) => *shared.lock().unwrap() = Some(sample),
+                                Err(e) => crate::dashboard::notice(format!("postgres sample failed: {}", e)),
+                            }
+                        }
+                    });
+                });
+                println!("monitoring postgres ({} table) every {}", WORKOUTS_TABLE, humantime::format_duration(interval));
+                Some(latest)
+            }
+
+            false => None,
+        };
+
+        Self { process, db, summary: ResourceSummary { server_pid: pid, ..Default::default() } }
+    }
+
+    /// samples the api server process. `None` if not monitoring one, or it can't be read.
+    pub fn sample_process(&mut self) -> Option<ProcessSample> {
+        let sample = self.process.as_mut()?.sample()?;
+        let peak = self.summary.peak_rss_bytes.get_or_insert(0);
+        *peak = (*peak).max(sample.rss_bytes);
+        self.summary.last_process_sample = Some(sample.clone());
+        Some(sample)
+    }
+
+    /// the postgres sample taken since the last call, if any
+    pub fn sample_db(&mut self) -> Option<DbSample> {
+        let sample = self.db.as_ref()?.lock().unwrap().take()?;
+        self.summary.last_db_sample = Some(sample.clone());
+        Some(sample)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.process.is_some() || self.db.is_some()
+    }
+
+    pub fn summary(&self) -> &ResourceSummary {
+        &self.summary
+    }
+}
+
+impl ProcessSample {
+    pub fn fmt_line(&self) -> String {
+        format!("server rss {} - cpu {:.0}% - {} open fds - {} threads",
+            fmt_bytes(self.rss_bytes as i64),
+            self.cpu_pct,
+            self.n_fds.thousands_sep(),
+            self.n_threads,
+        )
+    }
+}
+
+impl DbSample {
+    pub fn fmt_line(&self) -> String {
+        format!("postgres {}: ~{} rows ({} dead) - table {} - indexes {} - database {} - {} seq / {} idx scans",
+            WORKOUTS_TABLE,
+            self.n_live_tup.thousands_sep(),
+            self.n_dead_tup.thousands_sep(),
+            fmt_bytes(self.table_bytes),
+            fmt_bytes(self.index_bytes),
+            fmt_bytes(self.database_bytes),
+            self.seq_scan.thousands_sep(),
+            self.idx_scan.thousands_sep(),
+        )
+    }
+}
+
+pub fn fmt_bytes(n: i64) -> String {
+    const UNITS: &[&str] = &["B", "K", "M", "G", "T"];
+    let mut x = n as f64;
+    let mut unit = 0;
+    while x.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        x /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", x, UNITS[unit])
+}
@@ -0,0 +1,533 @@
+//! service-level objectives for stress-test
+//!
+//! each --slo is checked against a sliding window of recent intervals while the test runs,
+//! and against the whole run at the end. any violation fails the run.
+
+use std::time::*;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use structopt::StructOpt;
+use hdrhistogram::Histogram;
+
+use crate::stats::{self, Latencies};
+use crate::dashboard;
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct SloOpts {
+    /// service-level objective the run must meet (may be given more than once). examples:
+    ///
+    ///   "p99(/api/v1/workouts/list) < 30ms"
+    ///   "error_rate < 0.1%"
+    ///   "throughput >= 6000"
+    ///
+    /// latency metrics are p<n> (e.g. p50, p99.9), mean and max, over all requests (including
+    /// failed ones); units are us, ms (default) or s. error_rate is the percentage of requests
+    /// without a 2xx response (a read check that fails on a 2xx response isn't counted, since
+    /// any failed read check already fails the run). throughput is successful responses per
+    /// second. the endpoint in parentheses is optional; without it, the metric covers every
+    /// endpoint.
+    #[structopt(long = "slo", number_of_values = 1, verbatim_doc_comment)]
+    pub slos: Vec<Slo>,
+
+    /// length of the sliding window SLOs are continuously checked over
+    #[structopt(long, default_value = "10s", parse(try_from_str = parse_nonzero_duration))]
+    pub slo_window: Duration,
+}
+
+/// an empty window would never hold an interval to check
+fn parse_nonzero_duration(s: &str) -> Result<Duration, String> {
+    match humantime::parse_duration(s) {
+        Ok(d) if d > Duration::from_secs(0) => Ok(d),
+        Ok(_) => Err(format!("invalid value '{}' (expected a duration above 0)", s)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SloMetric {
+    /// latency percentile
+    Percentile(f64),
+    Mean,
+    Max,
+    ErrorRate,
+    Throughput,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+
+    fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Op::Lt => value < threshold,
+            Op::Le => value <= threshold,
+            Op::Gt => value > threshold,
+            Op::Ge => value >= threshold,
+        }
+    }
+}
+
+/// a single objective, e.g. "p99(/api/v1/workouts/list) < 30ms". latency thresholds are
+/// stored in ms, error rates in percent and throughput in req/s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slo {
+    pub metric: SloMetric,
+    pub endpoint: Option<String>,
+    pub op: Op,
+    pub threshold: f64,
+}
+
+impl Slo {
+    /// value of the metric over `latencies`, which were recorded over `secs` seconds. `None`
+    /// if there are no samples to evaluate.
+    pub fn value(&self, latencies: &Latencies, secs: f64) -> Option<f64> {
+        self.value_of(&Samples::of(latencies, self.endpoint.as_deref()), secs)
+    }
+
+    /// like `value`, but over `samples` (which must be for this slo's endpoint)
+    fn value_of(&self, samples: &Samples, secs: f64) -> Option<f64> {
+        let us_to_ms = |us: u64| us as f64 / 1000.0;
+        let hist = samples.hist.as_ref().filter(|h| ! h.is_empty());
+        match self.metric {
+            SloMetric::Percentile(p) => hist.map(|h| us_to_ms(h.value_at_percentile(p))),
+            SloMetric::Mean => hist.map(|h| h.mean() / 1000.0),
+            SloMetric::Max => hist.map(|h| us_to_ms(h.max())),
+            SloMetric::ErrorRate => {
+                if samples.n == 0 { return None }
+                Some((samples.n - samples.n_ok) as f64 / samples.n as f64 * 100.0)
+            }
+            SloMetric::Throughput => {
+                if secs <= 0.0 { return None }
+                Some(samples.n_ok as f64 / secs)
+            }
+        }
+    }
+
+    pub fn holds(&self, value: f64) -> bool {
+        self.op.holds(value, self.threshold)
+    }
+
+    fn fmt_value(&self, value: f64) -> String {
+        match self.metric {
+            SloMetric::ErrorRate => format!("{:.3}%", value),
+            SloMetric::Throughput => format!("{:.0} req/s", value),
+            _ => stats::fmt_us((value * 1000.0).round() as u64),
+        }
+    }
+}
+
+impl FromStr for Slo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |msg: &str| format!("invalid slo '{}': {}", s, msg);
+
+        let (op, op_pos, op_len) = [("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)].iter()
+            .filter_map(|(tok, op)| s.find(tok).map(|i| (*op, i, tok.len())))
+            .min_by_key(|(_, i, len)| (*i, std::cmp::Reverse(*len)))
+            .ok_or_else(|| err("expected one of <, <=, >, >="))?;
+        let lhs = s[..op_pos].trim();
+        let rhs = s[(op_pos + op_len)..].trim();
+
+        let (name, endpoint) = match lhs.find('(') {
+            Some(i) => {
+                if ! lhs.ends_with(')') { return Err(err("unbalanced parentheses")) }
+                let endpoint = lhs[(i + 1)..(lhs.len() - 1)].trim();
+                if endpoint.is_empty() { return Err(err("empty endpoint")) }
+                (lhs[..i].trim(), Some(endpoint.to_string()))
+            }
+            None => (lhs, None),
+        };
+
+        let metric = match name {
+            "mean" => SloMetric::Mean,
+            "max" => SloMetric::Max,
+            "error_rate" => SloMetric::ErrorRate,
+            "throughput" | "rps" => SloMetric::Throughput,
+            p if p.starts_with('p') => {
+                match p[1..].parse::<f64>() {
+                    Ok(p) if p > 0.0 && p <= 100.0 => SloMetric::Percentile(p),
+                    _ => return Err(err("invalid percentile (expected e.g. p99 or p99.9)")),
+                }
+            }
+            _ => return Err(err("unknown metric (expected p<n>, mean, max, error_rate or throughput)")),
+        };
+
+        let (number, scale) = match metric {
+            SloMetric::ErrorRate => (rhs.trim_end_matches('%'), 1.0),
+            SloMetric::Throughput => (rhs.trim_end_matches("req/s").trim_end_matches("rps"), 1.0),
+            _ => {
+                if let Some(x) = rhs.strip_suffix("us") {
+                    (x, 0.001)
+                } else if let Some(x) = rhs.strip_suffix("ms") {
+                    (x, 1.0)
+                } else if let Some(x) = rhs.strip_suffix('s') {
+                    (x, 1000.0)
+                } else {
+                    (rhs, 1.0)
+                }
+            }
+        };
+        let threshold = number.trim().parse::<f64>().map_err(|_| err("invalid threshold"))? * scale;
+
+        Ok(Slo { metric, endpoint, op, threshold })
+    }
+}
+
+impl fmt::Display for Slo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.metric {
+            SloMetric::Percentile(p) => write!(f, "p{}", p)?,
+            SloMetric::Mean => f.write_str("mean")?,
+            SloMetric::Max => f.write_str("max")?,
+            SloMetric::ErrorRate => f.write_str("error_rate")?,
+            SloMetric::Throughput => f.write_str("throughput")?,
+        }
+        if let Some(endpoint) = self.endpoint.as_ref() {
+            write!(f, "({})", endpoint)?;
+        }
+        write!(f, " {} {}", self.op.as_str(), self.fmt_value(self.threshold))
+    }
+}
+
+/// a period during which an slo was not met over the sliding window
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Violation {
+    /// seconds since the start of the run
+    pub start_secs: f64,
+    /// `None` if still violated when the run ended
+    pub end_secs: Option<f64>,
+    /// the value furthest from the threshold during the violation
+    pub worst_value: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SloResult {
+    pub slo: String,
+    /// value over the whole run
+    pub value: Option<f64>,
+    /// whether the slo was met over the whole run
+    pub met: bool,
+    /// sliding window violations
+    pub violations: Vec<Violation>,
+}
+
+impl SloResult {
+    pub fn passed(&self) -> bool {
+        self.met && self.violations.is_empty()
+    }
+}
+
+/// what an slo needs from a set of requests to the endpoint it covers
+#[derive(Clone, Default)]
+struct Samples {
+    /// latencies of every request, `None` if there were none
+    hist: Option<Histogram<u64>>,
+    n: u64,
+    n_ok: u64,
+}
+
+impl Samples {
+    fn of(latencies: &Latencies, endpoint: Option<&str>) -> Self {
+        let (n, n_ok) = latencies.counts(endpoint);
+        Samples { hist: latencies.combined(endpoint), n, n_ok }
+    }
+
+    fn add(&mut self, other: &Samples) {
+        self.n += other.n;
+        self.n_ok += other.n_ok;
+        match (self.hist.as_mut(), other.hist.as_ref()) {
+            (Some(hist), Some(other)) => hist.add(other).expect("histograms have the same bounds"),
+            (None, Some(other)) => self.hist = Some(other.clone()),
+            (_, None) => {}
+        }
+    }
+
+    /// removes `other`, which must have been added before
+    fn subtract(&mut self, other: &Samples) {
+        self.n -= other.n;
+        self.n_ok -= other.n_ok;
+        if let (Some(hist), Some(other)) = (self.hist.as_mut(), other.hist.as_ref()) {
+            hist.subtract(other).expect("subtracted samples were added before");
+        }
+    }
+}
+
+struct Tracked {
+    slo: Slo,
+    /// index into `SloMonitor::scopes`
+    scope: usize,
+    violations: Vec<Violation>,
+}
+
+/// checks slos over a sliding window of per-interval latencies
+pub struct SloMonitor {
+    tracked: Vec<Tracked>,
+    window: Duration,
+    /// each distinct endpoint the slos cover (`None` for every endpoint)
+    scopes: Vec<Option<String>>,
+    /// (interval length, samples of each scope in the interval), oldest first
+    recent: VecDeque<(Duration, Vec<Samples>)>,
+    /// samples of each scope over `recent`, kept up to date as intervals enter and leave it
+    in_window: Vec<Samples>,
+    /// total length of `recent`
+    covered: Duration,
+}
+
+impl SloMonitor {
+    pub fn new(opts: &SloOpts) -> Self {
+        for slo in opts.slos.iter() {
+            println!("slo: {} (checked over {} windows)", slo, humantime::format_duration(opts.slo_window));
+        }
+        let mut scopes: Vec<Option<String>> = Vec::new();
+        let tracked = opts.slos.iter().cloned().map(|slo| {
+            let scope = match scopes.iter().position(|x| *x == slo.endpoint) {
+                Some(i) => i,
+                None => {
+                    scopes.push(slo.endpoint.clone());
+                    scopes.len() - 1
+                }
+            };
+            Tracked { slo, scope, violations: Vec::new() }
+        }).collect();
+        Self {
+            tracked,
+            window: opts.slo_window,
+            in_window: vec![Samples::default(); scopes.len()],
+            scopes,
+            recent: VecDeque::new(),
+            covered: Duration::from_secs(0),
+        }
+    }
+
+    /// adds an interval to the sliding window and checks each slo over the window, printing
+    /// when a violation starts or ends. `elapsed` is the time since the start of the run.
+    pub fn observe(&mut self, elapsed: Duration, interval: Duration, latencies: &Latencies) {
+        if self.tracked.is_empty() { return }
+
+        let delta: Vec<Samples> = self.scopes.iter().map(|x| Samples::of(latencies, x.as_deref())).collect();
+        for (sum, x) in self.in_window.iter_mut().zip(delta.iter()) {
+            sum.add(x);
+        }
+        self.recent.push_back((interval, delta));
+        self.covered += interval;
+        while let Some(&(d, _)) = self.recent.front() {
+            if self.covered - d < self.window { break }
+            let (d, delta) = self.recent.pop_front().unwrap();
+            for (sum, x) in self.in_window.iter_mut().zip(delta.iter()) {
+                sum.subtract(x);
+            }
+            self.covered -= d;
+        }
+        let covered = self.covered;
+        // don't judge a window until it's full
+        if covered < self.window { return }
+
+        let now = elapsed.as_secs_f64();
+
+        for Tracked { slo, scope, violations } in self.tracked.iter_mut() {
+            let value = match slo.value_of(&self.in_window[*scope], covered.as_secs_f64()) {
+                Some(value) => value,
+                None => continue,
+            };
+            let ongoing = violations.last_mut().filter(|v| v.end_secs.is_none());
+            match (slo.holds(value), ongoing) {
+                (false, None) => {
+                    dashboard::notice(format!("SLO VIOLATED: {} - {} over last {:?} (at {:.0}s)", slo, slo.fmt_value(value), covered, now));
+                    violations.push(Violation { start_secs: now, end_secs: None, worst_value: value });
+                }
+
+                (false, Some(v)) => {
+                    // "worst" is whichever side of the threshold the slo forbids
+                    if ! slo.op.holds(value, v.worst_value) {
+                        v.worst_value = value;
+                    }
+                }
+
+                (true, Some(v)) => {
+                    dashboard::notice(format!("slo recovered: {} - {} over last {:?} (violated for {:.0}s, worst {})",
+                        slo, slo.fmt_value(value), covered, now - v.start_secs, slo.fmt_value(v.worst_value)));
+                    v.end_secs = Some(now);
+                }
+
+                (true, None) => {}
+            }
+        }
+    }
+
+    /// checks each slo over the whole run and prints a summary
+    pub fn finish(self, total: &Latencies, run_duration: Duration) -> Vec<SloResult> {
+        if self.tracked.is_empty() { return Vec::new() }
+
+        println!("slo results:");
+        self.tracked.into_iter()
+            .map(|Tracked { slo, violations, .. }| {
+                let value = slo.value(total, run_duration.as_secs_f64());
+                let met = value.map(|x| slo.holds(x)).unwrap_or(false);
+                let result = SloResult { slo: slo.to_string(), value, met, violations };
+                println!("    {:<8} {} - whole run: {} - {} window violation(s){}",
+                    if result.passed() { "ok" } else { "FAILED" },
+                    slo,
+                    value.map(|x| slo.fmt_value(x)).unwrap_or_else(|| "no data".to_string()),
+                    result.violations.len(),
+                    result.violations.iter()
+                        .map(|v| match v.end_secs {
+                            Some(end) => format!(" [{:.0}s-{:.0}s, worst {}]", v.start_secs, end, slo.fmt_value(v.worst_value)),
+                            None => format!(" [{:.0}s-end, worst {}]", v.start_secs, slo.fmt_value(v.worst_value)),
+                        })
+                        .collect::<String>(),
+                );
+                result
+            }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slo(s: &str) -> Slo {
+        s.parse().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn parses_latency_slos() {
+        assert_eq!(slo("p99(/api/v1/workouts/list) < 30ms"),
+            Slo { metric: SloMetric::Percentile(99.0), endpoint: Some("/api/v1/workouts/list".to_string()), op: Op::Lt, threshold: 30.0 });
+        assert_eq!(slo("p99.9<=250us"), Slo { metric: SloMetric::Percentile(99.9), endpoint: None, op: Op::Le, threshold: 0.25 });
+        assert_eq!(slo("p50 < 2s").threshold, 2000.0);
+        assert_eq!(slo("p100 < 7").threshold, 7.0);
+        assert_eq!(slo("mean ( /api/v1/workouts/new ) > 1.5ms"),
+            Slo { metric: SloMetric::Mean, endpoint: Some("/api/v1/workouts/new".to_string()), op: Op::Gt, threshold: 1.5 });
+        assert_eq!(slo("max >= 1s").metric, SloMetric::Max);
+    }
+
+    #[test]
+    fn parses_error_rate_and_throughput() {
+        assert_eq!(slo("error_rate < 0.1%"), Slo { metric: SloMetric::ErrorRate, endpoint: None, op: Op::Lt, threshold: 0.1 });
+        assert_eq!(slo("error_rate(/api/v1/workouts/new) <= 1").threshold, 1.0);
+        assert_eq!(slo("throughput >= 6000"), Slo { metric: SloMetric::Throughput, endpoint: None, op: Op::Ge, threshold: 6000.0 });
+        assert_eq!(slo("throughput > 500 req/s").threshold, 500.0);
+        assert_eq!(slo("rps >= 100rps"), Slo { metric: SloMetric::Throughput, endpoint: None, op: Op::Ge, threshold: 100.0 });
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in &["p99(/api/v1/workouts/list) < 30ms", "p99.9 <= 250us", "mean > 2s", "error_rate < 0.1%", "throughput >= 6000"] {
+            let x = slo(s);
+            assert_eq!(slo(&x.to_string()), x, "{}", s);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_slos() {
+        for s in &[
+            "p99 = 30ms",
+            "p99 30ms",
+            "p0 < 30ms",
+            "p101 < 30ms",
+            "px < 30ms",
+            "latency < 30ms",
+            "p99 < fast",
+            "p99 < 30 minutes",
+            "p99 <",
+            "p99(/api/v1/workouts/list < 30ms",
+            "p99() < 30ms",
+            "error_rate < 0.1 percent",
+        ] {
+            assert!(s.parse::<Slo>().is_err(), "'{}' parsed", s);
+        }
+    }
+
+    fn interval(took: Duration, n_ok: usize, n_failed: usize) -> Latencies {
+        let mut x = Latencies::default();
+        for _ in 0..n_ok { x.record("/api/v1/workouts/list", "200", took); }
+        for _ in 0..n_failed { x.record("/api/v1/workouts/new", "500", took); }
+        x
+    }
+
+    fn monitor(slos: &[&str], window: Duration) -> SloMonitor {
+        SloMonitor::new(&SloOpts { slos: slos.iter().map(|x| slo(x)).collect(), slo_window: window })
+    }
+
+    #[test]
+    fn violation_ends_once_it_leaves_the_window() {
+        let mut monitor = monitor(&["max < 10ms"], Duration::from_secs(2));
+        for (i, took) in [1, 1, 50, 1, 1].iter().enumerate() {
+            monitor.observe(Duration::from_secs(i as u64 + 1), Duration::from_secs(1), &interval(ms(*took), 10, 0));
+        }
+        let results = monitor.finish(&interval(ms(50), 50, 0), Duration::from_secs(5));
+        let violations = &results[0].violations;
+        assert_eq!(violations.len(), 1);
+        assert_eq!((violations[0].start_secs, violations[0].end_secs), (3.0, Some(5.0)));
+        assert!((violations[0].worst_value - 50.0).abs() < 0.1, "{}", violations[0].worst_value);
+        assert!( ! results[0].met);
+    }
+
+    #[test]
+    fn window_counts_only_recent_intervals() {
+        let mut monitor = monitor(&["error_rate < 10%", "throughput(/api/v1/workouts/list) >= 50"], Duration::from_secs(2));
+        // 80% errors, then none
+        monitor.observe(Duration::from_secs(1), Duration::from_secs(1), &interval(ms(1), 10, 40));
+        monitor.observe(Duration::from_secs(2), Duration::from_secs(1), &interval(ms(1), 100, 0));
+        monitor.observe(Duration::from_secs(3), Duration::from_secs(1), &interval(ms(1), 100, 0));
+        assert_eq!(monitor.in_window[0].n, 200);
+        assert_eq!(monitor.in_window[1].n, 200);
+
+        let results = monitor.finish(&Latencies::default(), Duration::from_secs(3));
+        let error_rate = &results[0].violations;
+        assert_eq!(error_rate.len(), 1);
+        assert_eq!((error_rate[0].start_secs, error_rate[0].end_secs), (2.0, Some(3.0)));
+        // 110 list requests over the first 2s
+        assert!(results[1].violations.is_empty());
+    }
+
+    #[test]
+    fn window_shorter_than_an_interval() {
+        let mut monitor = monitor(&["max < 10ms"], ms(500));
+        for (i, took) in [50, 1].iter().enumerate() {
+            monitor.observe(Duration::from_secs(i as u64 + 1), Duration::from_secs(1), &interval(ms(*took), 10, 0));
+            // the window always holds the latest interval
+            assert_eq!(monitor.recent.len(), 1);
+            assert_eq!(monitor.in_window[0].n, 10);
+        }
+        let results = monitor.finish(&interval(ms(1), 20, 0), Duration::from_secs(2));
+        let violations = &results[0].violations;
+        assert_eq!(violations.len(), 1);
+        assert_eq!((violations[0].start_secs, violations[0].end_secs), (1.0, Some(2.0)));
+    }
+
+    #[test]
+    fn zero_window_is_rejected() {
+        assert!(SloOpts::from_iter_safe(&["test", "--slo-window", "0s"]).is_err());
+        assert!(SloOpts::from_iter_safe(&["test", "--slo-window", "0ms"]).is_err());
+        assert_eq!(SloOpts::from_iter_safe(&["test", "--slo-window", "250ms"]).unwrap().slo_window, ms(250));
+    }
+
+    #[test]
+    fn no_data_is_not_met() {
+        let results = monitor(&["p99 < 30ms"], Duration::from_secs(10)).finish(&Latencies::default(), Duration::from_secs(1));
+        assert_eq!(results[0].value, None);
+        assert!( ! results[0].passed());
+    }
+}
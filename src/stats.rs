@@ -0,0 +1,151 @@
+//! in-process latency histograms, so percentiles are available without influx/grafana
+//!
+//! each worker records into a `LatencyShard` (via its `ApiClient`). the manager periodically
+//! drains the shards into an interval histogram, which it prints and then folds into the
+//! totals printed at shutdown.
+
+use std::time::*;
+use std::sync::{Arc, Mutex};
+use hashbrown::HashMap;
+use hdrhistogram::Histogram;
+
+/// latencies are recorded in microseconds
+const LOWEST_DISCERNIBLE_US: u64 = 1;
+
+/// anything slower than this is recorded as this
+const HIGHEST_TRACKABLE_US: u64 = 5 * 60 * 1_000_000;
+
+const SIGNIFICANT_FIGURES: u8 = 3;
+
+/// percentiles printed in the final table
+const TABLE_PERCENTILES: &[f64] = &[50.0, 90.0, 99.0, 99.9, 99.99];
+
+/// latency histograms shared between a manager and the worker(s) recording into them
+pub type LatencyShard = Arc<Mutex<Latencies>>;
+
+/// latency histograms keyed by (endpoint, status). status is the http status code, or
+/// "timeout" / "malformed" for requests that didn't produce a usable response.
+#[derive(Default, Clone)]
+pub struct Latencies {
+    hists: HashMap<(String, String), Histogram<u64>>,
+}
+
+impl Latencies {
+    pub fn record(&mut self, endpoint: &str, status: &str, took: Duration) {
+        self.hists.entry((endpoint.to_string(), status.to_string()))
+            .or_insert_with(new_histogram)
+            .saturating_record(took.as_micros() as u64);
+    }
+
+    /// adds all of `other`'s samples to `self`
+    pub fn merge(&mut self, other: &Latencies) {
+        for (key, hist) in other.hists.iter() {
+            if hist.is_empty() { continue }
+            self.hists.entry(key.clone())
+                .or_insert_with(new_histogram)
+                .add(hist)
+                .expect("histograms have the same bounds");
+        }
+    }
+
+    /// removes all samples, keeping the allocated histograms for reuse
+    pub fn clear(&mut self) {
+        for hist in self.hists.values_mut() {
+            hist.reset();
+        }
+    }
+
+    /// moves the samples recorded in each shard into `self`
+    pub fn drain_from(&mut self, shards: &[LatencyShard]) {
+        for shard in shards {
+            let mut shard = shard.lock().unwrap();
+            self.merge(&shard);
+            shard.clear();
+        }
+    }
+
+    /// non-empty histograms sorted by endpoint, then status
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, &Histogram<u64>)> {
+        let mut out: Vec<_> = self.hists.iter()
+            .filter(|(_, h)| ! h.is_empty())
+            .map(|((endpoint, status), h)| (endpoint.as_str(), status.as_str(), h))
+            .collect();
+        out.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        out.into_iter()
+    }
+
+    /// samples for `endpoint` (or every endpoint, if `None`) combined into one histogram
+    pub fn combined(&self, endpoint: Option<&str>) -> Option<Histogram<u64>> {
+        let mut out: Option<Histogram<u64>> = None;
+        for (ep, _, h) in self.iter() {
+            if endpoint.map(|x| x != ep).unwrap_or(false) { continue }
+            match out.as_mut() {
+                Some(out) => out.add(h).expect("histograms have the same bounds"),
+                None => out = Some(h.clone()),
+            }
+        }
+        out
+    }
+
+    /// (total, successful) number of requests to `endpoint` (or every endpoint, if `None`).
+    /// a request is successful if the response status was 2xx.
+    pub fn counts(&self, endpoint: Option<&str>) -> (u64, u64) {
+        let (mut n, mut n_ok) = (0, 0);
+        for (ep, status, h) in self.iter() {
+            if endpoint.map(|x| x != ep).unwrap_or(false) { continue }
+            n += h.len();
+            if status.starts_with('2') { n_ok += h.len(); }
+        }
+        (n, n_ok)
+    }
+
+    /// one line per endpoint/status, printed under the per-interval stats line
+    pub fn print_summary(&self) {
+        for (endpoint, status, h) in self.iter() {
+            println!("    {} {} - n={} p50 {} p99 {} p99.9 {} max {}",
+                endpoint,
+                status,
+                h.len(),
+                fmt_us(h.value_at_quantile(0.5)),
+                fmt_us(h.value_at_quantile(0.99)),
+                fmt_us(h.value_at_quantile(0.999)),
+                fmt_us(h.max()),
+            );
+        }
+    }
+
+    /// full percentile table, printed at shutdown
+    pub fn print_table(&self) {
+        let mut header = format!("{:<24} {:<10} {:>10} {:>10} {:>10}", "endpoint", "status", "count", "min", "mean");
+        for p in TABLE_PERCENTILES {
+            header.push_str(&format!(" {:>10}", format!("p{}", p)));
+        }
+        header.push_str(&format!(" {:>10}", "max"));
+        println!("{}", header);
+
+        for (endpoint, status, h) in self.iter() {
+            let mut row = format!("{:<24} {:<10} {:>10} {:>10} {:>10}",
+                endpoint,
+                status,
+                h.len(),
+                fmt_us(h.min()),
+                fmt_us(h.mean().round() as u64),
+            );
+            for p in TABLE_PERCENTILES {
+                row.push_str(&format!(" {:>10}", fmt_us(h.value_at_percentile(*p))));
+            }
+            row.push_str(&format!(" {:>10}", fmt_us(h.max())));
+            println!("{}", row);
+        }
+    }
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(LOWEST_DISCERNIBLE_US, HIGHEST_TRACKABLE_US, SIGNIFICANT_FIGURES)
+        .expect("valid histogram bounds")
+}
+
+/// formats a latency in microseconds as milliseconds
+pub fn fmt_us(us: u64) -> String {
+    format!("{:.2}ms", us as f64 / 1000.0)
+}